@@ -21,22 +21,54 @@
 // (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
 // OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
+use cgroup::{self, Cgroup};
 use libc;
-use limit::{setlimit, RLimit};
+use limit::{set_oom_score_adj, setlimit, RLimit, Resource};
+use namespace::Namespaces;
+use privilege::Privileges;
+use pty;
+use sandbox::Sandbox;
+use sched::Scheduling;
+use schedule::Schedule;
+use seccomp::Filter;
 use signal::signal;
+use socket::{Activation, Socket};
 use std::collections::HashMap;
-use std::fs;
+use std::fs::{self, File};
 use std::io;
+use std::mem;
 use std::os::unix::io::IntoRawFd;
 use std::os::unix::process::CommandExt;
 use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
 use stream;
+use usage::{self, Accounting, Usage};
 
 #[derive(Debug, PartialEq)]
 pub enum Mode {
     Simple,
     Forking,
     OneShot,
+    /// Runs to completion whenever its schedule says so
+    Scheduled(Schedule),
+}
+
+/// How long a forking or oneshot application's start command can run
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long an application can take to stop before it's killed
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where an application's standard input comes from
+#[derive(Debug, Clone)]
+pub enum Stdin {
+    Null,
+    Inherit,
+    File(String),
+    /// A new pseudo-terminal which is also used for stdout (and stderr
+    /// if that isn't redirected elsewhere)
+    Pty,
 }
 
 #[derive(Debug)]
@@ -46,14 +78,121 @@ pub struct Application {
     pub dir: String,
     pub pidfile: Option<String>,
     pub env: HashMap<String, String>,
+    pub stdin: Stdin,
     pub start: Vec<String>,
     pub stop: Vec<String>,
     pub healthchecks: Vec<String>,
     pub limits: Vec<RLimit>,
-    pub stdout: Option<stream::Stream>,
-    pub stderr: Option<stream::Stream>,
+    pub cgroup: Option<Cgroup>,
+    pub oom_score_adj: Option<i32>,
+    pub oom_kills: OomKills,
+    pub scheduling: Scheduling,
+    pub accounting: Accounting,
+    pub namespaces: Namespaces,
+    pub sandbox: Sandbox,
+    pub privileges: Privileges,
+    pub syscall_filter: Option<Filter>,
+    pub umask: Option<libc::mode_t>,
+    pub sockets: Vec<Arc<Socket>>,
+    pub on_demand: bool,
+    pub hooks: Hooks,
+    /// Exit statuses besides 0 which count as success
+    pub success_exit_status: ExitStatuses,
+    /// Exit statuses after which the application isn't restarted
+    pub restart_prevent_exit_status: ExitStatuses,
+    pub stdout: Vec<stream::Destination>,
+    pub stderr: Vec<stream::Destination>,
+    pub capture: stream::Capture,
     pub state: AppState,
     pub requires: Vec<String>,
+    /// Whether an `exec_start_post` command has failed since this was
+    /// last checked
+    pub hook_failed: bool,
+    /// Whether the application is being stopped after exiting with a
+    /// status in `restart_prevent_exit_status`
+    pub restart_prevented: bool,
+    /// Whether the application has been asked to stop while a hook
+    /// command was running
+    pub stop_pending: bool,
+}
+
+/// Exit codes and signals which a process's exit may be compared with
+#[derive(Debug, Default)]
+pub struct ExitStatuses {
+    pub codes: Vec<i32>,
+    pub signals: Vec<i32>,
+}
+
+impl ExitStatuses {
+    fn contains(&self, status: i32) -> bool {
+        if unsafe { libc::WIFEXITED(status) } {
+            self.codes.contains(&unsafe { libc::WEXITSTATUS(status) })
+        } else if unsafe { libc::WIFSIGNALED(status) } {
+            self.signals.contains(&unsafe { libc::WTERMSIG(status) })
+        } else {
+            false
+        }
+    }
+}
+
+/// Commands run before and after the application starts and stops
+#[derive(Debug, Default)]
+pub struct Hooks {
+    pub start_pre: Vec<Hook>,
+    pub start_post: Vec<Hook>,
+    pub stop_pre: Vec<Hook>,
+    pub stop_post: Vec<Hook>,
+    /// How long each command can run before it's killed
+    pub timeout: Duration,
+}
+
+#[derive(Debug)]
+pub struct Hook {
+    pub args: Vec<String>,
+    /// Whether the command failing is ignored
+    pub ignore_failure: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HookPhase {
+    StartPre,
+    StartPost,
+    StopPre,
+    StopPost,
+}
+
+impl HookPhase {
+    fn name(self) -> &'static str {
+        match self {
+            HookPhase::StartPre => "exec_start_pre",
+            HookPhase::StartPost => "exec_start_post",
+            HookPhase::StopPre => "exec_stop_pre",
+            HookPhase::StopPost => "exec_stop_post",
+        }
+    }
+}
+
+impl Hooks {
+    fn get(&self, phase: HookPhase) -> &[Hook] {
+        match phase {
+            HookPhase::StartPre => &self.start_pre,
+            HookPhase::StartPost => &self.start_post,
+            HookPhase::StopPre => &self.stop_pre,
+            HookPhase::StopPost => &self.stop_post,
+        }
+    }
+}
+
+/// Counts an application's processes killed by the OOM killer
+#[derive(Debug, Default)]
+pub struct OomKills {
+    pub count: u64,
+    /// Whether the last exit was an OOM kill, if it's not been taken
+    last_exit: Option<bool>,
+    /// The cgroup's count when the application started
+    cgroup_count: u64,
+    /// Whether riffol has SIGKILLed the application since it started
+    killed: bool,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -69,27 +208,47 @@ pub enum AppState {
         app_pid: Option<u32>,
         exec_pid: Option<u32>,
     },
+    /// Running the hook command `index` of `phase`, alongside the
+    /// application if it's running
+    Hook {
+        phase: HookPhase,
+        index: usize,
+        pid: u32,
+        app_pid: Option<u32>,
+    },
     Complete,
+    /// Exited with a status in `restart_prevent_exit_status`
+    Failed,
 }
 
 impl Application {
-    pub fn start(&mut self, stream_handler: &mut stream::Handler) -> bool {
-        self.start_process(&self.start)
+    pub fn start(&mut self, stream_handler: &stream::Handler) -> bool {
+        self.oom_kills.cgroup_count = self
+            .cgroup
+            .as_ref()
+            .and_then(|c| c.oom_kills().ok())
+            .unwrap_or(0);
+        self.oom_kills.killed = false;
+        self.stop_pending = false;
+        stream_handler.clear_output(&self.id);
+        self.run_hooks(HookPhase::StartPre, 0, None, stream_handler)
+    }
+
+    /// Starts the application's own process once its `exec_start_pre`
+    /// commands have run. Returns true if there's a process to wait
+    /// for before it's started.
+    fn launch(&mut self, stream_handler: &stream::Handler) -> bool {
+        self.start_process(&self.start, &self.stdin, self.cgroup.as_ref(), &self.sockets)
             .map_err(|e| warn!("Failed to start application {}: {:?}", self.id, e))
             .ok()
-            .map(|mut child| {
-                if let Some(stdout) = child.stdout.take().map(|s| s.into_raw_fd()) {
-                    stream_handler.add_stream(stdout, self.stdout.clone().unwrap());
-                }
-                if let Some(stderr) = child.stderr.take().map(|s| s.into_raw_fd()) {
-                    stream_handler.add_stream(stderr, self.stderr.clone().unwrap());
-                }
+            .map(|(mut child, pty)| {
+                self.accounting.start();
+                self.add_streams(&mut child, pty, stream_handler);
                 match self.mode {
                     Mode::Simple => {
-                        self.state = AppState::Running {
-                            app_pid: Some(child.id()),
-                        };
-                        false
+                        let app_pid = Some(child.id());
+                        self.state = AppState::Running { app_pid };
+                        self.run_hooks(HookPhase::StartPost, 0, app_pid, stream_handler)
                     }
                     _ => {
                         self.state = AppState::Starting {
@@ -101,22 +260,95 @@ impl Application {
             }).unwrap_or(false)
     }
 
-    pub fn stop(&mut self) -> bool {
-        let app_pid = self.get_app_pid();
+    /// Passes the output of `child`, one of the application's
+    /// processes, to its stream destinations
+    fn add_streams(&self, child: &mut Child, pty: Option<File>, stream_handler: &stream::Handler) {
+        let pid = child.id();
+        let source = |name| stream::Source {
+            app: self.id.to_owned(),
+            pid,
+            name,
+        };
+        if let Some(pty) = pty.map(|p| p.into_raw_fd()) {
+            let destinations = if self.stdout.is_empty() {
+                vec![stream::Destination::new(stream::Stream::Console)]
+            } else {
+                self.stdout.clone()
+            };
+            stream_handler.add_stream(pty, source("pty"), destinations, self.capture.clone());
+        }
+        if let Some(stdout) = child.stdout.take().map(|s| s.into_raw_fd()) {
+            stream_handler.add_stream(
+                stdout,
+                source("stdout"),
+                self.stdout.clone(),
+                self.capture.clone(),
+            );
+        }
+        if let Some(stderr) = child.stderr.take().map(|s| s.into_raw_fd()) {
+            stream_handler.add_stream(
+                stderr,
+                source("stderr"),
+                self.stderr.clone(),
+                self.capture.clone(),
+            );
+        }
+    }
+
+    pub fn stop(&mut self, stream_handler: &stream::Handler) -> bool {
+        match self.state {
+            // app_pid is Some(0) if the application has died
+            AppState::Running { app_pid } if app_pid != Some(0) => {
+                self.run_hooks(HookPhase::StopPre, 0, app_pid, stream_handler)
+            }
+            // the stop waits for the hook to finish
+            AppState::Hook { .. } => {
+                self.stop_pending = true;
+                true
+            }
+            _ => self.stop_app(stream_handler),
+        }
+    }
+
+    /// Stops the application once its `exec_stop_pre` commands have
+    /// run. Returns true if there's a process to wait for before it's
+    /// stopped.
+    fn stop_app(&mut self, stream_handler: &stream::Handler) -> bool {
+        // app_pid is Some(0) if the application has died
+        let app_pid = self.get_app_pid().filter(|&pid| pid != 0);
         if self.mode == Mode::OneShot {
             false
+        } else if let Mode::Scheduled(_) = self.mode {
+            // only a run that's in progress needs stopping
+            match self.state {
+                AppState::Starting { exec_pid } => {
+                    signal(exec_pid, libc::SIGTERM);
+                    self.state = AppState::Stopping {
+                        exec_pid: None,
+                        app_pid: Some(exec_pid),
+                    };
+                    true
+                }
+                _ => false,
+            }
         } else if self.mode == Mode::Simple {
-            signal(app_pid.unwrap(), libc::SIGTERM);
-            self.state = AppState::Stopping {
-                exec_pid: None,
-                app_pid,
-            };
-            true
+            match app_pid {
+                Some(pid) => {
+                    signal(pid, libc::SIGTERM);
+                    self.state = AppState::Stopping {
+                        exec_pid: None,
+                        app_pid,
+                    };
+                    true
+                }
+                None => self.stop_remaining(stream_handler),
+            }
         } else {
             let child = self
-                .start_process(&self.stop)
+                .start_process(&self.stop, &Stdin::Null, None, &[])
                 .map_err(|e| warn!("Failed to stop application {}: {:?}", self.id, e))
-                .ok();
+                .ok()
+                .map(|(child, _)| child);
             if let Some(child) = child {
                 self.state = AppState::Stopping {
                     exec_pid: Some(child.id()),
@@ -125,10 +357,7 @@ impl Application {
                 true
             } else {
                 match app_pid {
-                    None | Some(0) => {
-                        self.state = AppState::Idle;
-                        false
-                    }
+                    None => self.stop_remaining(stream_handler),
                     Some(pid) => {
                         signal(pid, libc::SIGTERM);
                         self.state = AppState::Stopping {
@@ -142,53 +371,190 @@ impl Application {
         }
     }
 
+    /// Stops any processes left in the application's cgroup. Returns
+    /// true if there are some to wait for.
+    fn stop_remaining(&mut self, stream_handler: &stream::Handler) -> bool {
+        if let Some(Err(e)) = self.cgroup.as_ref().map(|c| c.signal(libc::SIGTERM)) {
+            warn!("Failed to stop processes of {}: {}", self.id, e);
+        }
+        self.stopped(stream_handler);
+        !self.is_stopped()
+    }
+
+    /// Runs the `phase` commands from `index` on, one at a time.
+    /// Returns true if there's a process to wait for.
+    fn run_hooks(
+        &mut self,
+        phase: HookPhase,
+        index: usize,
+        app_pid: Option<u32>,
+        stream_handler: &stream::Handler,
+    ) -> bool {
+        let hook = match self.hooks.get(phase).get(index) {
+            Some(hook) => hook,
+            None => return self.hooks_done(phase, app_pid, stream_handler),
+        };
+        match self.start_process(&hook.args, &Stdin::Null, None, &[]) {
+            Ok((mut child, _)) => {
+                self.add_streams(&mut child, None, stream_handler);
+                self.state = AppState::Hook {
+                    phase,
+                    index,
+                    pid: child.id(),
+                    app_pid,
+                };
+                true
+            }
+            Err(e) => {
+                warn!("Failed to run {} command of {}: {:?}", phase.name(), self.id, e);
+                self.hook_finished(phase, index, false, app_pid, stream_handler)
+            }
+        }
+    }
+
+    /// Moves on from the hook command `index` of `phase`, which has
+    /// finished
+    fn hook_finished(
+        &mut self,
+        phase: HookPhase,
+        index: usize,
+        success: bool,
+        app_pid: Option<u32>,
+        stream_handler: &stream::Handler,
+    ) -> bool {
+        if success || self.hooks.get(phase)[index].ignore_failure {
+            return self.run_hooks(phase, index + 1, app_pid, stream_handler);
+        }
+        match phase {
+            HookPhase::StartPre => {
+                warn!("Application {} wasn't started", self.id);
+                self.stop_pending = false;
+                self.state = AppState::Idle;
+                false
+            }
+            // a running application is stopped as though it failed a
+            // healthcheck while a oneshot has failed
+            HookPhase::StartPost => {
+                let stop = mem::replace(&mut self.stop_pending, false);
+                self.state = match self.mode {
+                    Mode::Simple | Mode::Forking if stop => AppState::Running { app_pid },
+                    Mode::Simple | Mode::Forking => {
+                        self.hook_failed = true;
+                        AppState::Running { app_pid }
+                    }
+                    _ => AppState::Idle,
+                };
+                stop && self.stop(stream_handler)
+            }
+            // stopping carries on regardless
+            HookPhase::StopPre | HookPhase::StopPost => {
+                self.run_hooks(phase, index + 1, app_pid, stream_handler)
+            }
+        }
+    }
+
+    /// Carries on once all the `phase` commands have run
+    fn hooks_done(
+        &mut self,
+        phase: HookPhase,
+        app_pid: Option<u32>,
+        stream_handler: &stream::Handler,
+    ) -> bool {
+        // a stop asked for while starting is carried out now
+        let stop = mem::replace(&mut self.stop_pending, false);
+        match phase {
+            HookPhase::StartPre if stop => {
+                info!("Application {} stopped before it was started", self.id);
+                self.state = AppState::Idle;
+                false
+            }
+            HookPhase::StartPre => self.launch(stream_handler),
+            HookPhase::StartPost => {
+                self.state = match self.mode {
+                    Mode::OneShot => AppState::Complete,
+                    // a scheduled application waits for its next run
+                    Mode::Scheduled(_) => AppState::Idle,
+                    _ => AppState::Running { app_pid },
+                };
+                stop && self.stop(stream_handler)
+            }
+            HookPhase::StopPre => self.stop_app(stream_handler),
+            HookPhase::StopPost => {
+                self.state = if mem::replace(&mut self.restart_prevented, false) {
+                    AppState::Failed
+                } else {
+                    AppState::Idle
+                };
+                false
+            }
+        }
+    }
+
     pub fn kill(&mut self) {
-        let pid = match self.state {
-            AppState::Starting { exec_pid, .. } => exec_pid,
-            AppState::Running {
-                app_pid: Some(app_pid),
-                ..
-            } => app_pid,
+        // The whole cgroup is killed unless it's the stop process
+        // which has hung
+        let (pid, all) = match self.state {
+            AppState::Starting { exec_pid, .. } => (Some(exec_pid), true),
+            AppState::Running { app_pid, .. } => (app_pid, true),
             AppState::Stopping {
                 exec_pid: Some(exec_pid),
                 ..
-            } => exec_pid,
-            AppState::Stopping {
-                app_pid: Some(app_pid),
-                ..
-            } => app_pid,
+            } => (Some(exec_pid), false),
+            AppState::Stopping { app_pid, .. } => (app_pid, true),
+            // a hook isn't in the cgroup
+            AppState::Hook { pid, .. } => (Some(pid), false),
             _ => unreachable!(),
         };
-        signal(pid, libc::SIGKILL);
+        if let Some(pid) = pid.filter(|&pid| pid != 0) {
+            signal(pid, libc::SIGKILL);
+        }
+        self.oom_kills.killed |= all;
+        if let Some(cgroup) = self.cgroup.as_ref().filter(|_| all) {
+            if let Err(e) = cgroup.kill() {
+                warn!("Failed to kill processes of {}: {}", self.id, e);
+            }
+        }
     }
 
-    pub fn claim_child(&mut self, child: u32, status: i32) -> bool {
+    pub fn claim_child(
+        &mut self,
+        child: u32,
+        status: i32,
+        usage: &Usage,
+        stream_handler: &stream::Handler,
+    ) -> bool {
+        if self.owns(child) {
+            let main = self.get_app_pid() == Some(child);
+            self.accounting.add(usage, main);
+        }
         match self.state {
             AppState::Starting { exec_pid } if exec_pid == child => {
                 match self.mode {
-                    Mode::OneShot => {
-                        if status == 0 {
+                    Mode::OneShot | Mode::Scheduled(_) => {
+                        if self.is_success(status) {
                             info!("Application {} completed successfully", self.id);
-                            self.state = AppState::Complete;
+                            self.finish_run();
+                            self.run_hooks(HookPhase::StartPost, 0, None, stream_handler);
                         } else {
-                            warn!("Application {} failed. Exit code {}", self.id, status);
-                            self.state = AppState::Idle;
+                            let reason = self.exit_reason(Some(status));
+                            warn!("Application {} failed. {}", self.id, reason);
+                            self.state = self.failed_state(status);
+                            self.finish_run();
                         }
                     }
                     Mode::Forking => {
-                        if status == 0 {
+                        if self.is_success(status) {
                             info!("Application {} started successfully", self.id);
-                            let pid = self.read_pidfile();
-                            if pid == None {
+                            let pid = self.read_pidfile().or_else(|| self.guess_main_pid());
+                            if pid.is_none() && self.cgroup.is_none() {
                                 warn!("Couldn't read pidfile for {}", self.id);
                             }
-                            self.state = AppState::Running { app_pid: pid };
+                            self.run_hooks(HookPhase::StartPost, 0, pid, stream_handler);
                         } else {
-                            warn!(
-                                "Application {} failed to start. Exit code {}",
-                                self.id, status
-                            );
-                            self.state = AppState::Idle;
+                            let reason = self.exit_reason(Some(status));
+                            warn!("Application {} failed to start. {}", self.id, reason);
+                            self.state = self.failed_state(status);
+                            self.finish_run();
                         }
                     }
                     Mode::Simple => unreachable!(),
@@ -196,23 +562,14 @@ impl Application {
                 true
             }
             AppState::Running { app_pid: pid, .. } if pid == Some(child) => {
-                warn!(
-                    "Application {} died unexpectedly. Exit code {}",
-                    self.id, status
-                );
-                // This is an error regardless of exit status We need
-                // to run the exec stop command but can't do it from
-                // here as we'd bypass Init's timeouts so we need to
-                // signal a failure and Init can clean up ... hence Some(0)
-                self.state = AppState::Running { app_pid: Some(0) };
+                self.exited(child, status, stream_handler);
                 true
             }
             AppState::Stopping { app_pid, exec_pid }
                 if app_pid == Some(child) || exec_pid == Some(child) =>
             {
                 if app_pid.is_none() || exec_pid.is_none() {
-                    info!("Application {} stopped", self.id);
-                    self.state = AppState::Idle;
+                    self.stopped(stream_handler);
                 } else if app_pid == Some(child) {
                     self.state = AppState::Stopping {
                         app_pid: None,
@@ -220,7 +577,11 @@ impl Application {
                     }
                 } else {
                     if status != 0 {
-                        warn!("Application {} stop failed. Exit code {}", self.id, status);
+                        warn!(
+                            "Application {} stop failed. {}",
+                            self.id,
+                            describe_status(status, &[])
+                        );
                     }
                     self.state = AppState::Stopping {
                         app_pid,
@@ -229,25 +590,304 @@ impl Application {
                 }
                 true
             }
+            AppState::Hook {
+                phase,
+                index,
+                pid,
+                app_pid,
+            } if pid == child =>
+            {
+                if status != 0 && !self.hooks.get(phase)[index].ignore_failure {
+                    warn!(
+                        "Application {} {} command failed. {}",
+                        self.id,
+                        phase.name(),
+                        describe_status(status, &[])
+                    );
+                }
+                self.hook_finished(phase, index, status == 0, app_pid, stream_handler);
+                true
+            }
+            AppState::Hook {
+                phase,
+                index,
+                pid,
+                app_pid,
+            } if app_pid == Some(child) =>
+            {
+                // the application exited while a hook was running.
+                // It's only unexpected if it was starting.
+                let app_pid = match phase {
+                    HookPhase::StartPost => {
+                        self.exited(child, status, stream_handler);
+                        Some(0)
+                    }
+                    _ => None,
+                };
+                self.state = AppState::Hook {
+                    phase,
+                    index,
+                    pid,
+                    app_pid,
+                };
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the application's processes are only tracked by its
+    /// cgroup, which has to be checked to notice them exiting
+    pub fn is_cgroup_only(&self) -> bool {
+        self.cgroup.is_some()
+            && matches!(
+                self.state,
+                AppState::Running { app_pid: None }
+                    | AppState::Stopping {
+                        app_pid: None,
+                        exec_pid: None,
+                    }
+            )
+    }
+
+    /// Checks on an application whose processes are only tracked by
+    /// its cgroup. Returns true if its state changed.
+    pub fn check_cgroup(&mut self, stream_handler: &stream::Handler) -> bool {
+        let populated = match self.cgroup.as_ref() {
+            Some(cgroup) if self.is_cgroup_only() => cgroup.is_populated().unwrap_or(true),
+            _ => return false,
+        };
+        match self.state {
+            AppState::Running { app_pid: None } if !populated => {
+                let reason = self.exit_reason(None);
+                self.died(&reason, None, stream_handler);
+                true
+            }
+            AppState::Stopping {
+                app_pid: None,
+                exec_pid: None,
+            } if !populated => {
+                self.stopped(stream_handler);
+                true
+            }
             _ => false,
         }
     }
 
+    /// Samples the resource use of the application's main process
+    pub fn sample_usage(&mut self) {
+        if let Some(pid) = self.get_app_pid().filter(|&pid| pid != 0) {
+            match usage::sample(pid) {
+                Ok(usage) => self.accounting.sample(&usage),
+                Err(e) => debug!("Couldn't sample usage of {}: {}", self.id, e),
+            }
+        }
+    }
+
+    /// Logs the lines most recently captured from the application
+    pub fn report_output(&self, stream_handler: &stream::Handler) {
+        let recent = stream_handler.recent_output(&self.id);
+        if recent.is_empty() {
+            info!("Application {} has no recent output", self.id);
+        } else {
+            info!(
+                "Application {} recent output:\n{}",
+                self.id,
+                recent.join("\n")
+            );
+        }
+    }
+
+    /// Logs the resources used by the application's runs so far
+    pub fn report_usage(&self) {
+        let (usage, run_time) = self.accounting.total();
+        info!(
+            "Application {}: {} runs, {}, run time {:.3}s",
+            self.id,
+            self.accounting.runs,
+            usage,
+            usage::secs(run_time)
+        );
+    }
+
+    fn finish_run(&mut self) {
+        if let Some((usage, run_time)) = self.accounting.finish() {
+            info!(
+                "Application {} used {} and ran for {:.3}s",
+                self.id,
+                usage,
+                usage::secs(run_time)
+            );
+        }
+    }
+
+    /// Whether the application's last exit was an OOM kill. Returns
+    /// `None` if there's been no exit since this was last called.
+    pub fn take_oom_kill(&mut self) -> Option<bool> {
+        self.oom_kills.last_exit.take()
+    }
+
+    /// Describes how the application's process exited (`status` is
+    /// `None` if all that's known is that its cgroup is empty), noting
+    /// whether the OOM killer was responsible.
+    fn exit_reason(&mut self, status: Option<i32>) -> String {
+        let signal = status
+            .filter(|&s| unsafe { libc::WIFSIGNALED(s) })
+            .map(|s| unsafe { libc::WTERMSIG(s) });
+        let oom = match self.cgroup.as_ref().and_then(|c| c.oom_kills().ok()) {
+            Some(count) => count > mem::replace(&mut self.oom_kills.cgroup_count, count),
+            // without the cgroup's count a SIGKILL we didn't send most
+            // likely came from the OOM killer unless a CPU time limit
+            // was hit
+            None => {
+                signal == Some(libc::SIGKILL)
+                    && !self.oom_kills.killed
+                    && !self.limits.iter().any(|l| l.resource == Resource::Cpu)
+            }
+        };
+        self.oom_kills.last_exit = Some(oom);
+        if oom {
+            self.oom_kills.count += 1;
+            format!(
+                "Killed by the OOM killer ({} OOM kills)",
+                self.oom_kills.count
+            )
+        } else {
+            status.map_or_else(
+                || "No processes remain".to_owned(),
+                |s| describe_status(s, &self.limits),
+            )
+        }
+    }
+
+    fn is_success(&self, status: i32) -> bool {
+        status == 0 || self.success_exit_status.contains(status)
+    }
+
+    /// The state a starting application is left in when it fails
+    fn failed_state(&self, status: i32) -> AppState {
+        if self.restart_prevent_exit_status.contains(status) {
+            warn!("Application {} won't be restarted", self.id);
+            AppState::Failed
+        } else {
+            AppState::Idle
+        }
+    }
+
+    /// Deals with the running application's main process, `pid`,
+    /// exiting
+    fn exited(&mut self, pid: u32, status: i32, stream_handler: &stream::Handler) {
+        let reason = self.exit_reason(Some(status));
+        if self.restart_prevent_exit_status.contains(status) {
+            warn!(
+                "Application {} exited and won't be restarted. {}",
+                self.id, reason
+            );
+            self.restart_prevented = true;
+            self.state = AppState::Running { app_pid: Some(0) };
+        } else if self.is_success(status) {
+            info!("Application {} exited. {}", self.id, reason);
+            self.state = AppState::Running { app_pid: Some(0) };
+        } else {
+            self.died(&reason, Some(pid), stream_handler);
+        }
+    }
+
+    /// Logs the application's unexpected exit along with its recent
+    /// output, including all of `pid`'s if that's the process which
+    /// exited
+    fn died(&mut self, reason: &str, pid: Option<u32>, stream_handler: &stream::Handler) {
+        stream_handler.report(
+            &self.id,
+            pid,
+            format!("Application {} died unexpectedly. {}", self.id, reason),
+        );
+        // This is an error regardless of exit status We need
+        // to run the exec stop command but can't do it from
+        // here as we'd bypass Init's timeouts so we need to
+        // signal a failure and Init can clean up ... hence Some(0)
+        self.state = AppState::Running { app_pid: Some(0) };
+    }
+
+    /// The application's known processes have exited but it isn't
+    /// stopped until any left in its cgroup have too.
+    fn stopped(&mut self, stream_handler: &stream::Handler) {
+        match self.cgroup.as_ref() {
+            Some(cgroup) if cgroup.is_populated().unwrap_or(false) => {
+                info!("Waiting for remaining processes of {}", self.id);
+                self.state = AppState::Stopping {
+                    app_pid: None,
+                    exec_pid: None,
+                };
+            }
+            cgroup => {
+                if let Some(Err(e)) = cgroup.map(|c| c.remove()) {
+                    debug!("Couldn't remove cgroup for {}: {}", self.id, e);
+                }
+                info!("Application {} stopped", self.id);
+                self.finish_run();
+                self.run_hooks(HookPhase::StopPost, 0, None, stream_handler);
+            }
+        }
+    }
+
     pub fn is_idle(&self) -> bool {
         self.state == AppState::Idle
     }
 
     pub fn is_stopped(&self) -> bool {
-        self.state == AppState::Idle || self.state == AppState::Complete
+        match self.state {
+            AppState::Idle | AppState::Complete | AppState::Failed => true,
+            _ => false,
+        }
     }
 
     pub fn is_started(&self) -> bool {
         match self.state {
             AppState::Complete | AppState::Running { .. } => true,
+            // a scheduled application doesn't hold up those that
+            // require it, whether or not it's running
+            AppState::Idle | AppState::Starting { .. } | AppState::Hook { .. } => {
+                self.schedule().is_some()
+            }
             _ => false,
         }
     }
 
+    /// The pid of the hook command being run, if there is one
+    pub fn hook_pid(&self) -> Option<u32> {
+        match self.state {
+            AppState::Hook { pid, .. } => Some(pid),
+            _ => None,
+        }
+    }
+
+    /// Whether an `exec_start_post` command has failed since this was
+    /// last called
+    pub fn take_hook_failure(&mut self) -> bool {
+        mem::replace(&mut self.hook_failed, false)
+    }
+
+    /// How long the application can stay in its current state before
+    /// it's killed, if there's a limit
+    pub fn timeout(&self) -> Option<Duration> {
+        match self.state {
+            AppState::Hook { .. } => Some(self.hooks.timeout),
+            // scheduled runs take as long as they need
+            AppState::Starting { .. } if self.schedule().is_some() => None,
+            AppState::Starting { .. } => Some(START_TIMEOUT),
+            AppState::Stopping { .. } => Some(STOP_TIMEOUT),
+            _ => None,
+        }
+    }
+
+    pub fn schedule(&self) -> Option<&Schedule> {
+        match self.mode {
+            Mode::Scheduled(ref schedule) => Some(schedule),
+            _ => None,
+        }
+    }
+
     pub fn is_runaway(&self) -> bool {
         match self.state {
             AppState::Stopping { exec_pid: None, .. } => true,
@@ -255,6 +895,15 @@ impl Application {
         }
     }
 
+    /// Whether the application is stopping and only waiting for
+    /// processes left in its cgroup
+    pub fn is_draining(&self) -> bool {
+        self.state == AppState::Stopping {
+            app_pid: None,
+            exec_pid: None,
+        }
+    }
+
     pub fn is_dead(&self) -> bool {
         match self.state {
             AppState::Running { app_pid: Some(0) } => true,
@@ -262,37 +911,166 @@ impl Application {
         }
     }
 
+    /// Whether `pid` is one of the application's processes
+    fn owns(&self, pid: u32) -> bool {
+        match self.state {
+            AppState::Starting { exec_pid } => exec_pid == pid,
+            AppState::Running { app_pid } => app_pid == Some(pid),
+            AppState::Stopping { app_pid, exec_pid } => {
+                app_pid == Some(pid) || exec_pid == Some(pid)
+            }
+            AppState::Hook {
+                pid: hook_pid,
+                app_pid,
+                ..
+            } => hook_pid == pid || app_pid == Some(pid),
+            _ => false,
+        }
+    }
+
     fn get_app_pid(&self) -> Option<u32> {
         match self.state {
             AppState::Running { app_pid, .. } => app_pid,
             AppState::Stopping { app_pid, .. } => app_pid,
+            AppState::Hook { app_pid, .. } => app_pid,
             _ => None,
         }
     }
 
-    fn start_process(&self, args: &[String]) -> io::Result<Child> {
-        fn stdio(stream: &Option<stream::Stream>) -> Stdio {
-            stream
-                .as_ref()
-                .map(|stream| match stream {
-                    stream::Stream::File { filename: f } if f == "/dev/null" => Stdio::null(),
-                    _ => Stdio::piped(),
-                }).unwrap_or_else(Stdio::inherit)
+    /// Spawns `args` as a child process, in `cgroup` if given. If
+    /// `stdin` is `Stdin::Pty` the master side of the child's
+    /// pseudo-terminal is also returned.
+    fn start_process(
+        &self,
+        args: &[String],
+        stdin: &Stdin,
+        cgroup: Option<&Cgroup>,
+        sockets: &[Arc<Socket>],
+    ) -> io::Result<(Child, Option<File>)> {
+        fn stdio(destinations: &[stream::Destination]) -> Stdio {
+            let is_null = |d: &stream::Destination| match &d.stream {
+                stream::Stream::File { filename: f, .. } => f == "/dev/null",
+                _ => false,
+            };
+            if destinations.is_empty() {
+                Stdio::inherit()
+            } else if destinations.iter().all(is_null) {
+                Stdio::null()
+            } else {
+                Stdio::piped()
+            }
         }
 
         let limits = self.limits.clone();
+        let oom_score_adj = self.oom_score_adj.map(|adj| adj.to_string());
+        let scheduling = self.scheduling.clone();
+        let namespaces = self.namespaces.clone();
+        let sandbox = self.sandbox.clone();
+        let privileges = self.privileges.clone();
+        let syscall_filter = self.syscall_filter.clone();
+        let umask = self.umask;
+        let mut activation = match sockets.len() {
+            0 => None,
+            _ => Some(Activation::new(sockets.iter().map(|s| &**s), &self.env)),
+        };
+        let procs = match cgroup {
+            Some(cgroup) => {
+                cgroup.create()?;
+                Some(cgroup.procs()?)
+            }
+            None => None,
+        };
+        let mut command = Command::new(&args[0]);
+        let mut master = None;
+
+        match stdin {
+            Stdin::Null => {
+                command.stdin(Stdio::null());
+            }
+            Stdin::Inherit => {
+                command.stdin(Stdio::inherit());
+            }
+            Stdin::File(filename) => {
+                command.stdin(File::open(filename)?);
+            }
+            Stdin::Pty => {
+                let (pty, slave) = pty::open_pty()?;
+                command.stdin(slave.try_clone()?);
+                command.stdout(slave.try_clone()?);
+                if self.stderr.is_empty() {
+                    command.stderr(slave);
+                }
+                master = Some(pty);
+            }
+        }
+        if master.is_none() {
+            command.stdout(stdio(&self.stdout));
+        }
+        if master.is_none() || !self.stderr.is_empty() {
+            command.stderr(stdio(&self.stderr));
+        }
 
-        Command::new(&args[0])
-            .current_dir(&self.dir)
-            .env_clear()
-            .envs(self.env.iter())
+        // with a new root the working directory is changed to once
+        // inside it
+        if self.sandbox.root.is_none() {
+            command.current_dir(&self.dir);
+        }
+        // an application passed sockets gets its environment, which
+        // includes its pid, from the activation
+        if activation.is_none() {
+            command.env_clear().envs(self.env.iter());
+        }
+        let controlling_terminal = master.is_some();
+        // this runs in the child between fork and exec, where another
+        // of riffol's threads may have held the allocator's lock, so
+        // nothing called here may allocate. Anything that needs
+        // allocating is prepared above.
+        command
             .before_exec(move || {
-                limits.iter().for_each(|l| setlimit(l));
+                if let Some(procs) = procs.as_ref() {
+                    cgroup::join(procs)?;
+                }
+                for limit in &limits {
+                    setlimit(limit)?;
+                }
+                if let Some(adj) = oom_score_adj.as_ref() {
+                    set_oom_score_adj(adj)?;
+                }
+                scheduling.apply()?;
+                if let Some(mask) = umask {
+                    unsafe { libc::umask(mask) };
+                }
+                namespaces.enter()?;
+                sandbox.apply()?;
+                if controlling_terminal {
+                    pty::set_controlling_terminal()?;
+                }
+                // after the above as dropping privileges may prevent them
+                privileges.apply()?;
+                namespaces.die_with_proxy()?;
+                if let Some(activation) = activation.as_mut() {
+                    activation.apply()?;
+                }
+                // last so the filter needn't allow anything but exec
+                if let Some(filter) = syscall_filter.as_ref() {
+                    filter.install()?;
+                }
                 Ok(())
-            }).stdout(stdio(&self.stdout))
-            .stderr(stdio(&self.stderr))
-            .args(&args[1..])
+            }).args(&args[1..])
             .spawn()
+            .map(|child| (child, master))
+    }
+
+    /// The main process of a forking application without a pidfile
+    /// is the only process left in its cgroup
+    fn guess_main_pid(&self) -> Option<u32> {
+        self.cgroup
+            .as_ref()
+            .and_then(|cgroup| cgroup.pids().ok())
+            .and_then(|pids| match pids.len() {
+                1 => Some(pids[0]),
+                _ => None,
+            })
     }
 
     fn read_pidfile(&self) -> Option<u32> {
@@ -311,5 +1089,213 @@ impl Application {
     }
 }
 
+/// Describes a wait status, adding which resource limit was probably
+/// exceeded if that's why a process was killed
+fn describe_status(status: i32, limits: &[RLimit]) -> String {
+    let (exited, signalled) = unsafe { (libc::WIFEXITED(status), libc::WIFSIGNALED(status)) };
+    if exited {
+        return format!("Exit code {}", unsafe { libc::WEXITSTATUS(status) });
+    } else if !signalled {
+        return format!("Wait status {}", status);
+    }
+    let signal = unsafe { libc::WTERMSIG(status) };
+    let limited = |resources: &[Resource]| limits.iter().any(|l| resources.contains(&l.resource));
+    let context = match signal {
+        libc::SIGXCPU => " (CPU time limit exceeded)",
+        libc::SIGKILL if limited(&[Resource::Cpu]) => " (CPU time limit probably exceeded)",
+        libc::SIGXFSZ => " (file size limit exceeded)",
+        libc::SIGSYS => " (system call not allowed by syscall_filter or invalid)",
+        libc::SIGSEGV | libc::SIGBUS | libc::SIGABRT
+            if limited(&[Resource::As, Resource::Data, Resource::Stack]) =>
+        {
+            " (memory limit possibly exceeded)"
+        }
+        _ => "",
+    };
+    format!("Killed by signal {}{}", signal, context)
+}
+
 #[cfg(test)]
-mod tests {}
+mod test {
+    use super::{describe_status, AppState, Application, Hook, Mode, Stdin};
+    use cgroup::Cgroup;
+    use libc;
+    use limit::{Limit, RLimit, Resource};
+    use signal::signal;
+    use std::collections::HashMap;
+    use stream;
+    use usage::Usage;
+
+    fn application(mode: Mode, start: &[&str]) -> Application {
+        Application {
+            id: "test".to_owned(),
+            mode,
+            dir: "/".to_owned(),
+            pidfile: None,
+            env: HashMap::new(),
+            stdin: Stdin::Null,
+            start: start.iter().map(|s| s.to_string()).collect(),
+            stop: vec![],
+            healthchecks: vec![],
+            limits: vec![],
+            cgroup: None,
+            oom_score_adj: None,
+            oom_kills: Default::default(),
+            scheduling: Default::default(),
+            accounting: Default::default(),
+            namespaces: Default::default(),
+            sandbox: Default::default(),
+            privileges: Default::default(),
+            syscall_filter: None,
+            umask: None,
+            sockets: vec![],
+            on_demand: false,
+            hooks: Default::default(),
+            success_exit_status: Default::default(),
+            restart_prevent_exit_status: Default::default(),
+            stdout: vec![],
+            stderr: vec![],
+            capture: Default::default(),
+            state: AppState::Idle,
+            requires: vec![],
+            hook_failed: false,
+            restart_prevented: false,
+            stop_pending: false,
+        }
+    }
+
+    /// Waits for `pid` to exit and passes it to the application
+    fn reap(app: &mut Application, pid: u32, stream_handler: &stream::Handler) {
+        let mut status = 0;
+        unsafe { libc::waitpid(pid as libc::pid_t, &mut status, 0) };
+        assert!(app.claim_child(pid, status, &Usage::default(), stream_handler));
+    }
+
+    /// Waits for the application's starting process to exit and
+    /// passes it to the application
+    fn reap_starting(app: &mut Application, stream_handler: &stream::Handler) {
+        match app.state {
+            AppState::Starting { exec_pid } => reap(app, exec_pid, stream_handler),
+            ref state => panic!("{:?} isn't starting", state),
+        }
+    }
+
+    #[test]
+    fn test_oom_kill() {
+        let stream_handler = &stream::Handler::new();
+        let mut app = application(Mode::OneShot, &["/bin/sleep", "10"]);
+
+        // riffol killing a start which has taken too long isn't an
+        // OOM kill
+        assert!(app.start(stream_handler));
+        app.kill();
+        reap_starting(&mut app, stream_handler);
+        assert_eq!(app.take_oom_kill(), Some(false));
+        assert_eq!(app.oom_kills.count, 0);
+
+        // but without a cgroup any other SIGKILL probably is
+        assert!(app.start(stream_handler));
+        if let AppState::Starting { exec_pid } = app.state {
+            signal(exec_pid, libc::SIGKILL);
+        }
+        reap_starting(&mut app, stream_handler);
+        assert_eq!(app.take_oom_kill(), Some(true));
+        assert_eq!(app.oom_kills.count, 1);
+    }
+
+    #[test]
+    fn test_cgroup_only() {
+        let mut app = application(Mode::Forking, &["/bin/true"]);
+        app.state = AppState::Running { app_pid: None };
+        assert!(!app.is_cgroup_only());
+        app.cgroup = Some(Cgroup::new("/nonexistent", "test", vec![]));
+        assert!(app.is_cgroup_only());
+        app.state = AppState::Running { app_pid: Some(1) };
+        assert!(!app.is_cgroup_only());
+        app.state = AppState::Stopping {
+            app_pid: None,
+            exec_pid: None,
+        };
+        assert!(app.is_cgroup_only());
+    }
+
+    #[test]
+    fn test_describe_status() {
+        let cpu = RLimit {
+            resource: Resource::Cpu,
+            soft: Some(Limit::Num(1)),
+            hard: None,
+        };
+        assert_eq!(describe_status(3 << 8, &[]), "Exit code 3");
+        assert_eq!(describe_status(9, &[]), "Killed by signal 9");
+        let limits = vec![cpu];
+        assert_eq!(
+            describe_status(9, &limits),
+            "Killed by signal 9 (CPU time limit probably exceeded)"
+        );
+        assert_eq!(
+            describe_status(24, &limits),
+            "Killed by signal 24 (CPU time limit exceeded)"
+        );
+        assert_eq!(describe_status(11, &[]), "Killed by signal 11");
+        assert_eq!(
+            describe_status(31, &[]),
+            "Killed by signal 31 (system call not allowed by syscall_filter or invalid)"
+        );
+    }
+
+    #[test]
+    fn test_stop_during_hook() {
+        let stream_handler = &stream::Handler::new();
+        let mut app = application(Mode::Simple, &["/bin/sleep", "10"]);
+        app.hooks.start_post.push(Hook {
+            args: vec!["/bin/true".to_owned()],
+            ignore_failure: false,
+        });
+
+        // the stop is carried out once the exec_start_post command
+        // has finished
+        assert!(app.start(stream_handler));
+        let (hook_pid, app_pid) = match app.state {
+            AppState::Hook { pid, app_pid, .. } => (pid, app_pid.unwrap()),
+            ref state => panic!("{:?} isn't running a hook", state),
+        };
+        assert!(app.stop(stream_handler));
+        reap(&mut app, hook_pid, stream_handler);
+        assert_eq!(
+            app.state,
+            AppState::Stopping {
+                app_pid: Some(app_pid),
+                exec_pid: None,
+            }
+        );
+        reap(&mut app, app_pid, stream_handler);
+        assert!(app.is_idle());
+    }
+
+    #[test]
+    fn test_exit_status() {
+        let stream_handler = &stream::Handler::new();
+        let mut app = application(Mode::OneShot, &["/bin/sh", "-c", "exit 0"]);
+        app.success_exit_status.codes.push(3);
+        app.success_exit_status.signals.push(libc::SIGTERM);
+        app.restart_prevent_exit_status.codes.push(4);
+        assert!(app.is_success(0) && app.is_success(3 << 8) && app.is_success(libc::SIGTERM));
+        assert!(!app.is_success(4 << 8) && !app.is_success(libc::SIGKILL));
+
+        // 0 is a success alongside success_exit_status
+        assert!(app.start(stream_handler));
+        reap_starting(&mut app, stream_handler);
+        assert_eq!(app.state, AppState::Complete);
+
+        app.start[2] = "exit 3".to_owned();
+        assert!(app.start(stream_handler));
+        reap_starting(&mut app, stream_handler);
+        assert_eq!(app.state, AppState::Complete);
+
+        app.start[2] = "exit 4".to_owned();
+        assert!(app.start(stream_handler));
+        reap_starting(&mut app, stream_handler);
+        assert_eq!(app.state, AppState::Failed);
+    }
+}
@@ -0,0 +1,323 @@
+// Copyright (c) 2018, [Ribose Inc](https://www.ribose.com).
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// ``AS IS'' AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use libc;
+use limit::Limit;
+use signal::signal;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup/riffol";
+
+/// The period used for `cpu.max` quotas, in microseconds
+const CPU_PERIOD: u64 = 100_000;
+
+/// A cgroup v2 control file setting
+#[derive(Debug, Clone, PartialEq)]
+pub enum Control {
+    MemoryMax(Limit),
+    MemoryHigh(Limit),
+    /// CPU time allowed per `CPU_PERIOD`, in microseconds
+    CpuMax(Limit),
+    CpuWeight(u64),
+    PidsMax(Limit),
+    /// A device, "MAJ:MIN", and its `rbps`, `wbps`, `riops` and
+    /// `wiops` settings
+    IoMax(String, Vec<(String, Limit)>),
+}
+
+impl Control {
+    fn controller(&self) -> &'static str {
+        match self {
+            Control::MemoryMax(_) | Control::MemoryHigh(_) => "memory",
+            Control::CpuMax(_) | Control::CpuWeight(_) => "cpu",
+            Control::PidsMax(_) => "pids",
+            Control::IoMax(..) => "io",
+        }
+    }
+
+    fn filename(&self) -> &'static str {
+        match self {
+            Control::MemoryMax(_) => "memory.max",
+            Control::MemoryHigh(_) => "memory.high",
+            Control::CpuMax(_) => "cpu.max",
+            Control::CpuWeight(_) => "cpu.weight",
+            Control::PidsMax(_) => "pids.max",
+            Control::IoMax(..) => "io.max",
+        }
+    }
+
+    fn value(&self) -> String {
+        let limit = |l: &Limit| match l {
+            Limit::Num(n) => n.to_string(),
+            Limit::Infinity => "max".to_owned(),
+        };
+        match self {
+            Control::MemoryMax(l) | Control::MemoryHigh(l) | Control::PidsMax(l) => limit(l),
+            Control::CpuMax(l) => format!("{} {}", limit(l), CPU_PERIOD),
+            Control::CpuWeight(w) => w.to_string(),
+            Control::IoMax(device, settings) => settings.iter().fold(device.to_owned(), |s, (k, v)| {
+                format!("{} {}={}", s, k, limit(v))
+            }),
+        }
+    }
+
+    /// Whether `other` sets the same thing as `self`
+    pub fn overlaps(&self, other: &Control) -> bool {
+        match (self, other) {
+            (Control::IoMax(a, _), Control::IoMax(b, _)) => a == b,
+            _ => self.filename() == other.filename(),
+        }
+    }
+
+    /// The more restrictive of two overlapping controls. `io.max`
+    /// settings are combined, taking the lower of any set in both.
+    pub fn lower(&self, other: &Control) -> Control {
+        match (self, other) {
+            (Control::MemoryMax(a), Control::MemoryMax(b)) => Control::MemoryMax(a.lower(*b)),
+            (Control::MemoryHigh(a), Control::MemoryHigh(b)) => Control::MemoryHigh(a.lower(*b)),
+            (Control::CpuMax(a), Control::CpuMax(b)) => Control::CpuMax(a.lower(*b)),
+            (Control::CpuWeight(a), Control::CpuWeight(b)) => Control::CpuWeight(*a.min(b)),
+            (Control::PidsMax(a), Control::PidsMax(b)) => Control::PidsMax(a.lower(*b)),
+            (Control::IoMax(device, a), Control::IoMax(_, b)) => {
+                let mut settings = a.clone();
+                for (k, v) in b {
+                    match settings.iter_mut().find(|(key, _)| key == k) {
+                        Some(setting) => setting.1 = setting.1.lower(*v),
+                        None => settings.push((k.to_owned(), *v)),
+                    }
+                }
+                Control::IoMax(device.to_owned(), settings)
+            }
+            _ => other.clone(),
+        }
+    }
+}
+
+/// An application's cgroup
+#[derive(Debug, Clone)]
+pub struct Cgroup {
+    pub path: PathBuf,
+    pub controls: Vec<Control>,
+}
+
+impl Cgroup {
+    pub fn new<P: AsRef<Path>>(root: P, name: &str, controls: Vec<Control>) -> Self {
+        Cgroup {
+            path: root.as_ref().join(name),
+            controls,
+        }
+    }
+
+    /// Creates the cgroup, enabling the controllers it needs in its
+    /// parent, and applies its controls.
+    pub fn create(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.path)?;
+        let mut controllers = self
+            .controls
+            .iter()
+            .map(|c| c.controller())
+            .collect::<Vec<_>>();
+        controllers.sort();
+        controllers.dedup();
+        if let Some(parent) = self.path.parent() {
+            // the parent's parent must delegate the controllers to
+            // the parent too, which it may well already do
+            if let Some(grandparent) = parent.parent() {
+                let _ = enable_controllers(grandparent, &controllers);
+            }
+            enable_controllers(parent, &controllers)?;
+        }
+        self.controls
+            .iter()
+            .try_for_each(|c| write_file(&self.path.join(c.filename()), &c.value()))
+    }
+
+    /// Opens the cgroup's process list. Writing "0" to it (see
+    /// `join`) moves the writer into the cgroup.
+    pub fn procs(&self) -> io::Result<File> {
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(self.path.join("cgroup.procs"))
+    }
+
+    /// The processes in the cgroup
+    pub fn pids(&self) -> io::Result<Vec<u32>> {
+        fs::read_to_string(self.path.join("cgroup.procs"))
+            .map(|s| s.lines().filter_map(|l| l.trim().parse().ok()).collect())
+    }
+
+    /// Whether there are any processes in the cgroup or its
+    /// descendants
+    pub fn is_populated(&self) -> io::Result<bool> {
+        match fs::read_to_string(self.path.join("cgroup.events")) {
+            Ok(events) => Ok(events.lines().any(|l| l.trim() == "populated 1")),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(!self.pids()?.is_empty()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The number of processes in the cgroup killed by the OOM
+    /// killer, from `memory.events`
+    pub fn oom_kills(&self) -> io::Result<u64> {
+        fs::read_to_string(self.path.join("memory.events")).map(|events| {
+            events
+                .lines()
+                .filter_map(|l| {
+                    let kv = l.split_whitespace().collect::<Vec<_>>();
+                    match kv.as_slice() {
+                        ["oom_kill", n] => n.parse().ok(),
+                        _ => None,
+                    }
+                }).next()
+                .unwrap_or(0)
+        })
+    }
+
+    /// Sends `sig` to every process in the cgroup
+    pub fn signal(&self, sig: i32) -> io::Result<()> {
+        self.pids().map(|pids| pids.iter().for_each(|&pid| signal(pid, sig)))
+    }
+
+    /// Kills every process in the cgroup. `cgroup.kill` is used if
+    /// the kernel has it as it can't be raced by forking processes.
+    pub fn kill(&self) -> io::Result<()> {
+        let kill = self.path.join("cgroup.kill");
+        if kill.exists() {
+            write_file(&kill, "1")
+        } else {
+            self.signal(libc::SIGKILL)
+        }
+    }
+
+    /// Removes the cgroup which must have no processes
+    pub fn remove(&self) -> io::Result<()> {
+        fs::remove_dir(&self.path)
+    }
+}
+
+/// Moves the calling process into the cgroup whose `procs` file is
+/// given. Only does a single write so is safe to call between fork
+/// and exec.
+pub fn join(mut procs: &File) -> io::Result<()> {
+    procs.write_all(b"0")
+}
+
+fn enable_controllers(dir: &Path, controllers: &[&str]) -> io::Result<()> {
+    if controllers.is_empty() {
+        return Ok(());
+    }
+    let value = controllers
+        .iter()
+        .map(|c| format!("+{}", c))
+        .collect::<Vec<_>>()
+        .join(" ");
+    write_file(&dir.join("cgroup.subtree_control"), &value)
+}
+
+fn write_file(path: &Path, value: &str) -> io::Result<()> {
+    // cgroup control files are written with a single write
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .and_then(|mut f| f.write_all(value.as_bytes()))
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{join, Cgroup, Control};
+    use limit::Limit;
+    use std::fs;
+
+    #[test]
+    fn test_cgroup() {
+        // a plain directory stands in for the cgroup filesystem
+        let base = ::std::env::temp_dir().join(format!("riffol-cgroup-{}", ::std::process::id()));
+        let root = base.join("riffol");
+        let cgroup = Cgroup::new(
+            &root,
+            "app",
+            vec![
+                Control::MemoryMax(Limit::Num(512 << 20)),
+                Control::MemoryHigh(Limit::Infinity),
+                Control::CpuMax(Limit::Num(50_000)),
+                Control::CpuWeight(200),
+                Control::PidsMax(Limit::Num(64)),
+                Control::IoMax(
+                    "8:0".to_owned(),
+                    vec![("rbps".to_owned(), Limit::Num(1 << 20))],
+                ),
+            ],
+        );
+        cgroup.create().unwrap();
+        let read = |f: &str| fs::read_to_string(root.join(f)).unwrap();
+        assert_eq!(read("cgroup.subtree_control"), "+cpu +io +memory +pids");
+        assert_eq!(read("app/memory.max"), "536870912");
+        assert_eq!(read("app/memory.high"), "max");
+        assert_eq!(read("app/cpu.max"), "50000 100000");
+        assert_eq!(read("app/cpu.weight"), "200");
+        assert_eq!(read("app/pids.max"), "64");
+        assert_eq!(read("app/io.max"), "8:0 rbps=1048576");
+        join(&cgroup.procs().unwrap()).unwrap();
+        assert_eq!(read("app/cgroup.procs"), "0");
+        assert_eq!(cgroup.pids().unwrap(), vec![0]);
+        fs::write(root.join("app/cgroup.procs"), "").unwrap();
+        assert!(!cgroup.is_populated().unwrap());
+        fs::write(root.join("app/cgroup.events"), "populated 1\nfrozen 0\n").unwrap();
+        assert!(cgroup.is_populated().unwrap());
+        assert!(cgroup.oom_kills().is_err());
+        fs::write(root.join("app/memory.events"), "low 0\noom 3\noom_kill 2\n").unwrap();
+        assert_eq!(cgroup.oom_kills().unwrap(), 2);
+        fs::write(root.join("app/cgroup.kill"), "").unwrap();
+        cgroup.kill().unwrap();
+        assert_eq!(read("app/cgroup.kill"), "1");
+        assert!(cgroup.remove().is_err());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_lower() {
+        let a = Control::IoMax("8:0".to_owned(), vec![("rbps".to_owned(), Limit::Num(10))]);
+        let b = Control::IoMax(
+            "8:0".to_owned(),
+            vec![
+                ("rbps".to_owned(), Limit::Num(5)),
+                ("wbps".to_owned(), Limit::Num(20)),
+            ],
+        );
+        assert!(a.overlaps(&b));
+        assert_eq!(a.lower(&b), b);
+        let a = Control::PidsMax(Limit::Num(10));
+        assert!(!a.overlaps(&b));
+        assert_eq!(
+            a.lower(&Control::PidsMax(Limit::Infinity)),
+            Control::PidsMax(Limit::Num(10))
+        );
+    }
+}
@@ -22,15 +22,30 @@
 // OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
 use application::{self, AppState, Mode};
+use cgroup::{self, Cgroup, Control};
 use health::{DfCheck, HealthCheck, IntervalHealthCheck, ProcCheck, TcpCheck};
-use limit::{Limit, RLimit};
+use libc;
+use limit::{self, Limit, RLimit, Resource, Unit};
+use namespace;
+use privilege;
 use nereon::{self, FromValue, Value};
+use regex::Regex;
+use sandbox;
+use sched;
+use schedule;
+use socket;
+use seccomp;
+use signal;
 use std::collections::HashMap;
+use std::ffi::CString;
 use std::iter::Iterator;
 use std::net::SocketAddr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::str::FromStr;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{env, fs};
 use stream;
 use syslog;
@@ -47,6 +62,8 @@ struct Config {
     application: HashMap<String, Application>,
     healthchecks: HashMap<String, HealthChecks>,
     limits: HashMap<String, Limits>,
+    socket: HashMap<String, Socket>,
+    cgroup_root: Option<String>,
 }
 
 #[derive(FromValue)]
@@ -68,20 +85,200 @@ struct Environment {
 #[derive(FromValue)]
 struct Application {
     mode: Option<String>,
+    schedule: Option<String>,
+    interval: Option<u64>,
+    overlap: Option<Overlap>,
     dir: Option<String>,
     pidfile: Option<String>,
     env: Option<Environment>,
     env_file: Option<String>,
+    stdin: Option<Stdin>,
     start: Vec<String>,
     stop: Vec<String>,
+    exec_start_pre: Vec<Vec<String>>,
+    exec_start_post: Vec<Vec<String>>,
+    exec_stop_pre: Vec<Vec<String>>,
+    exec_stop_post: Vec<Vec<String>>,
+    hook_timeout: Option<u64>,
+    success_exit_status: Vec<String>,
+    restart_prevent_exit_status: Vec<String>,
     healthchecks: Vec<String>,
     limits: Vec<String>,
-    stdout: Option<Stream>,
-    stderr: Option<Stream>,
+    stdout: Destinations,
+    stderr: Destinations,
+    capture: Option<Capture>,
+    oom_score_adj: Option<i32>,
+    nice: Option<i32>,
+    cpu_affinity: Option<String>,
+    sched_policy: Option<SchedPolicy>,
+    ioprio: Option<IoPriority>,
+    namespaces: Vec<Namespace>,
+    root_directory: Option<String>,
+    read_write_paths: Vec<String>,
+    read_only_paths: Vec<String>,
+    inaccessible_paths: Vec<String>,
+    private_tmp: Option<Flag>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    capabilities: Vec<String>,
+    capability_bounding_set: Option<Vec<String>>,
+    no_new_privileges: Option<Flag>,
+    syscall_filter: Option<SyscallFilter>,
+    umask: Option<String>,
+    sockets: Vec<String>,
+    on_demand: Option<Flag>,
+    instances: Option<u32>,
     requires: Vec<String>,
 }
 
-type Limits = HashMap<String, u64>;
+/// System calls to allow or deny, by name or group
+#[derive(FromValue)]
+struct SyscallFilter {
+    allow: Option<Vec<String>>,
+    deny: Option<Vec<String>>,
+    action: Option<FilterAction>,
+}
+
+#[derive(FromValue)]
+enum FilterAction {
+    Kill,
+    Errno(u32),
+}
+
+/// What a scheduled application does about a run that's still going
+/// when the next is due
+#[derive(FromValue)]
+enum Overlap {
+    Skip,
+    Queue,
+    Kill,
+}
+
+#[derive(FromValue)]
+enum Flag {
+    True,
+    False,
+}
+
+/// A namespace to start an application in. A user namespace's id maps
+/// are given as `{ user { uid_map [..], gid_map [..] } }`.
+enum Namespace {
+    Mount,
+    Pid,
+    Net,
+    Ipc,
+    Uts,
+    User(UserNamespace),
+}
+
+#[derive(FromValue)]
+struct UserNamespace {
+    uid_map: Vec<String>,
+    gid_map: Vec<String>,
+}
+
+impl FromValue for Namespace {
+    fn from_value(v: Value) -> Result<Self, String> {
+        let user = |v| UserNamespace::from_value(v).map(Namespace::User);
+        match v {
+            Value::String(ref s) if s == "user" => user(Value::Table(HashMap::new())),
+            Value::String(s) => match s.as_ref() {
+                "mount" => Ok(Namespace::Mount),
+                "pid" => Ok(Namespace::Pid),
+                "net" => Ok(Namespace::Net),
+                "ipc" => Ok(Namespace::Ipc),
+                "uts" => Ok(Namespace::Uts),
+                _ => Err(format!("No such namespace \"{}\"", s)),
+            },
+            Value::Table(mut t) => match t.remove("user") {
+                Some(v) if t.is_empty() => user(v),
+                _ => Err("Only a user namespace takes a value".to_owned()),
+            },
+            _ => Err("Not a namespace".to_owned()),
+        }
+    }
+}
+
+#[derive(FromValue)]
+enum SchedPolicy {
+    Other,
+    Batch,
+    Idle,
+    Fifo(u32),
+    Rr(u32),
+}
+
+#[derive(FromValue)]
+enum IoPriority {
+    Rt(u32),
+    Be(u32),
+    Idle,
+}
+
+#[derive(FromValue)]
+enum Stdin {
+    Null,
+    Inherit,
+    File(String),
+    Pty,
+}
+
+#[derive(FromValue)]
+struct Capture {
+    max_line: Option<u64>,
+    overlong: Option<Overlong>,
+    encoding: Option<Encoding>,
+    history: Option<u64>,
+}
+
+#[derive(FromValue)]
+enum Overlong {
+    Split,
+    Truncate,
+}
+
+#[derive(FromValue)]
+enum Encoding {
+    Lossy,
+    Escape,
+}
+
+type Limits = HashMap<String, LimitValue>;
+
+/// A resource limit, either a single value used for both the soft and
+/// hard limits or `{ soft value, hard value }`.
+#[derive(Clone)]
+enum LimitValue {
+    Both(String),
+    Split {
+        soft: Option<String>,
+        hard: Option<String>,
+    },
+}
+
+impl FromValue for LimitValue {
+    fn from_value(mut v: Value) -> Result<Self, String> {
+        if let Some(s) = v.as_str() {
+            return Ok(LimitValue::Both(s.to_owned()));
+        }
+        let mut field = |k| v.as_table_mut().and_then(|t| t.remove(k));
+        let soft = field("soft");
+        let hard = field("hard");
+        match (Value::convert(soft)?, Value::convert(hard)?) {
+            (None, None) => Err("A limit needs a value or soft and/or hard values".to_owned()),
+            (soft, hard) => Ok(LimitValue::Split { soft, hard }),
+        }
+    }
+}
+
+/// A listening socket, `tcp://address:port` or `unix://path`, passed
+/// to applications
+#[derive(FromValue)]
+struct Socket {
+    listen: String,
+    mode: Option<String>,
+    owner: Option<String>,
+}
 
 #[derive(FromValue)]
 struct HealthChecks {
@@ -126,20 +323,112 @@ enum SyslogFacility {
     LOCAL7,
 }
 
+/// How a `file` stream's file is created
+#[derive(FromValue)]
+struct LogFile {
+    mode: Option<String>,
+    owner: Option<String>,
+}
+
 #[derive(FromValue)]
 enum Stream {
-    File(String),
+    Console,
+    File(String, Option<LogFile>),
     Syslog {
         socket: Option<String>,
         facility: Option<SyslogFacility>,
         severity: Option<SyslogSeverity>,
+        severity_rules: Vec<SeverityRule>,
     },
     RSyslog {
         server: String,
         local: Option<String>,
         facility: Option<SyslogFacility>,
         severity: Option<SyslogSeverity>,
+        severity_rules: Vec<SeverityRule>,
     },
+    Journal {
+        socket: Option<String>,
+        identifier: Option<String>,
+        facility: Option<SyslogFacility>,
+        severity: Option<SyslogSeverity>,
+        severity_rules: Vec<SeverityRule>,
+    },
+}
+
+#[derive(FromValue)]
+enum SeverityRule {
+    Prefix,
+    Key(String),
+    Pattern {
+        regex: String,
+        severity: SyslogSeverity,
+    },
+}
+
+/// A `Stream` along with options common to all stream types. The
+/// options sit alongside the stream type, eg.
+/// `stdout { file ["/var/log/app"], multiline {} }`
+struct Destination {
+    stream: Stream,
+    multiline: Option<Multiline>,
+    queue: Option<Queue>,
+    rate_limit: Option<RateLimit>,
+    timestamp: Option<String>,
+}
+
+impl FromValue for Destination {
+    fn from_value(mut v: Value) -> Result<Self, String> {
+        let mut option = |k| v.as_table_mut().and_then(|t| t.remove(k));
+        let multiline = option("multiline");
+        let queue = option("queue");
+        let rate_limit = option("rate_limit");
+        let timestamp = option("timestamp");
+        Ok(Destination {
+            multiline: Value::convert(multiline)?,
+            queue: Value::convert(queue)?,
+            rate_limit: Value::convert(rate_limit)?,
+            timestamp: Value::convert(timestamp)?,
+            stream: Stream::from_value(v)?,
+        })
+    }
+}
+
+/// One or more `Destination`s. A single destination needn't be
+/// enclosed in a list.
+struct Destinations(Vec<Destination>);
+
+impl FromValue for Destinations {
+    fn from_value(v: Value) -> Result<Self, String> {
+        match v {
+            Value::List(_) => Vec::from_value(v).map(Destinations),
+            _ => Destination::from_value(v).map(|d| Destinations(vec![d])),
+        }
+    }
+
+    fn from_no_value() -> Result<Self, String> {
+        Ok(Destinations(Vec::new()))
+    }
+}
+
+#[derive(FromValue)]
+struct Multiline {
+    pattern: Option<String>,
+    timeout: Option<u64>,
+}
+
+#[derive(FromValue)]
+struct RateLimit {
+    lines: Option<u64>,
+    bytes: Option<u64>,
+    interval: Option<u64>,
+    burst: Option<u64>,
+}
+
+#[derive(FromValue)]
+struct Queue {
+    size: Option<u64>,
+    overflow: Option<String>,
 }
 
 #[derive(Debug)]
@@ -173,79 +462,22 @@ pub fn get_config<T: IntoIterator<Item = String>>(args: T) -> Result<Riffol, Str
         applications: Vec::new(),
         healthchecks: Vec::new(),
     };
+    // sockets are bound when first used and shared by applications
+    let mut sockets = HashMap::new();
 
-    for (_, init) in config.init {
-        for group_name in init.application_groups {
-            match config.application_group.get(&group_name) {
+    for init in config.init.values() {
+        for group_name in &init.application_groups {
+            match config.application_group.get(group_name) {
                 Some(group) => {
-                    for id in &group.applications {
-                        match config.application.get(id) {
-                            Some(ap) => {
-                                let mode = ap.mode.as_ref().map_or_else(
-                                    || Ok(Mode::Simple),
-                                    |s| match s.as_ref() {
-                                        "simple" => Ok(Mode::Simple),
-                                        "forking" => Ok(Mode::Forking),
-                                        "oneshot" => Ok(Mode::OneShot),
-                                        _ => Err(format!("Invalid application mode ({})", s)),
-                                    },
-                                )?;
-
-                                let healthchecks = ap.healthchecks.clone();
-                                let limits = match get_limits(&config.limits, &ap.limits) {
-                                    Ok(ls) => ls,
-                                    Err(e) => return Err(e),
-                                };
-                                let mut env = ap
-                                    .env_file
-                                    .as_ref()
-                                    .map_or_else(|| Ok(HashMap::new()), |f| read_env_file(&f))?;
-
-                                if let Some(ref vars) = ap.env {
-                                    vars.pass.iter().for_each(|(old, new)| {
-                                        if let Ok(value) = env::var(old) {
-                                            env.insert(new.to_owned(), value.to_owned());
-                                        }
-                                    });
-                                    vars.new.iter().for_each(|(k, v)| {
-                                        env.insert(k.to_owned(), v.to_owned());
-                                    });
-                                }
-
-                                let stderr = match ap.stderr.as_ref() {
-                                    None => None,
-                                    Some(s) => match mk_stream(&s) {
-                                        Ok(s) => Some(s),
-                                        Err(e) => return Err(format!("Invalid stream {}", e)),
-                                    },
-                                };
-
-                                let stdout = match ap.stdout.as_ref() {
-                                    None => None,
-                                    Some(s) => match mk_stream(&s) {
-                                        Ok(s) => Some(s),
-                                        Err(e) => return Err(format!("Invalid stream {}", e)),
-                                    },
-                                };
-
-                                riffol.applications.push(application::Application {
-                                    id: id.to_owned(),
-                                    mode,
-                                    dir: ap.dir.clone().unwrap_or_else(|| "/tmp".to_owned()),
-                                    pidfile: ap.pidfile.clone(),
-                                    env,
-                                    start: ap.start.clone(),
-                                    stop: ap.stop.clone(),
-                                    healthchecks,
-                                    limits,
-                                    stdout,
-                                    stderr,
-                                    /* TODO: check requires are valid */
-                                    requires: ap.requires.clone(),
-                                    state: AppState::Idle,
-                                });
-                            }
-                            None => return Err(format!("No such application \"{}\"", id)),
+                    for name in &group.applications {
+                        let ap = config
+                            .application
+                            .get(name)
+                            .ok_or_else(|| format!("No such application \"{}\"", name))?;
+                        for instance in instances(ap)? {
+                            riffol
+                                .applications
+                                .push(mk_application(&config, name, ap, instance, &mut sockets)?);
                         }
                     }
                 }
@@ -271,6 +503,206 @@ pub fn get_config<T: IntoIterator<Item = String>>(args: T) -> Result<Riffol, Str
     Ok(riffol)
 }
 
+/// Builds an application from its configuration `ap` under `name`,
+/// as `instance` if it's a template. Sockets are shared between
+/// applications through `sockets`.
+fn mk_application(
+    config: &Config,
+    name: &str,
+    ap: &Application,
+    instance: Option<u32>,
+    sockets: &mut HashMap<String, Arc<socket::Socket>>,
+) -> Result<application::Application, String> {
+    let id = &instance_id(name, instance);
+    let mode = ap.mode.as_ref().map_or_else(
+        || Ok(Mode::Simple),
+        |s| match s.as_ref() {
+            "simple" => Ok(Mode::Simple),
+            "forking" => Ok(Mode::Forking),
+            "oneshot" => Ok(Mode::OneShot),
+            "scheduled" => mk_schedule(ap).map(Mode::Scheduled),
+            _ => Err(format!("Invalid application mode ({})", s)),
+        },
+    )?;
+    let scheduled = matches!(mode, Mode::Scheduled(_));
+    if !scheduled && (ap.schedule.is_some() || ap.interval.is_some() || ap.overlap.is_some()) {
+        return Err(format!(
+            "Application {} can only have a schedule, interval or overlap if it's scheduled",
+            id
+        ));
+    }
+
+    let healthchecks = ap.healthchecks.clone();
+    let (limits, controls) = get_limits(&config.limits, &ap.limits)?;
+    // applications only get a cgroup if it has been configured or is
+    // needed
+    let cgroup = match (&config.cgroup_root, controls.is_empty()) {
+        (None, true) => None,
+        (root, _) => Some(Cgroup::new(
+            root.as_ref().map_or(cgroup::CGROUP_ROOT, |r| r.as_str()),
+            id,
+            controls,
+        )),
+    };
+    let oom_score_adj = match ap.oom_score_adj {
+        Some(adj) if !(-1000..=1000).contains(&adj) => {
+            return Err(format!("Invalid oom_score_adj ({})", adj))
+        }
+        adj => adj,
+    };
+    let scheduling = mk_scheduling(ap)?;
+    let namespaces = mk_namespaces(&ap.namespaces)?;
+    if namespaces.pid && mode == Mode::Forking {
+        return Err(format!("Application {} can't be forking with a pid namespace", id));
+    }
+    let dir = ap.dir.clone().unwrap_or_else(|| "/tmp".to_owned());
+    let sandbox = mk_sandbox(ap, &dir)?;
+    let privileges = mk_privileges(ap)?;
+    let syscall_filter = match ap.syscall_filter.as_ref() {
+        // installing a filter sets no_new_privileges
+        Some(_) if matches!(ap.no_new_privileges, Some(Flag::False)) => {
+            return Err(format!(
+                "Application {} has a syscall_filter so can't have no_new_privileges false",
+                id
+            ));
+        }
+        Some(filter) => Some(mk_syscall_filter(filter)?),
+        None => None,
+    };
+    let mut env = ap
+        .env_file
+        .as_ref()
+        .map_or_else(|| Ok(HashMap::new()), |f| read_env_file(&f))?;
+
+    if let Some(ref vars) = ap.env {
+        vars.pass.iter().for_each(|(old, new)| {
+            if let Ok(value) = env::var(old) {
+                env.insert(new.to_owned(), value.to_owned());
+            }
+        });
+        vars.new.iter().for_each(|(k, v)| {
+            env.insert(k.to_owned(), instantiate(v, instance));
+        });
+    }
+
+    let umask = match ap.umask.as_ref() {
+        Some(umask) => Some(parse_mode(umask, 0o777, "umask")?),
+        None => None,
+    };
+    let app_sockets = ap
+        .sockets
+        .iter()
+        .map(|name| get_socket(&config.socket, sockets, name))
+        .collect::<Result<Vec<_>, _>>()?;
+    let on_demand = matches!(ap.on_demand, Some(Flag::True));
+    if on_demand && app_sockets.is_empty() {
+        return Err(format!("Application {} can't be on_demand without sockets", id));
+    }
+    let mut stderr =
+        mk_destinations(&ap.stderr, umask).map_err(|e| format!("Invalid stream {}", e))?;
+    let mut stdout =
+        mk_destinations(&ap.stdout, umask).map_err(|e| format!("Invalid stream {}", e))?;
+    instantiate_destinations(&mut stderr, instance);
+    instantiate_destinations(&mut stdout, instance);
+
+    let hooks = mk_hooks(ap, instance)?;
+    let success_exit_status = mk_exit_statuses(&ap.success_exit_status)?;
+    let restart_prevent_exit_status = mk_exit_statuses(&ap.restart_prevent_exit_status)?;
+
+    let capture = ap
+        .capture
+        .as_ref()
+        .map_or_else(|| Ok(stream::Capture::default()), mk_capture)?;
+
+    Ok(application::Application {
+        id: id.to_owned(),
+        mode,
+        dir,
+        pidfile: ap.pidfile.as_ref().map(|p| instantiate(p, instance)),
+        env,
+        stdin: match ap.stdin.as_ref() {
+            None | Some(Stdin::Null) => application::Stdin::Null,
+            Some(Stdin::Inherit) => application::Stdin::Inherit,
+            Some(Stdin::File(f)) => application::Stdin::File(f.to_owned()),
+            Some(Stdin::Pty) => application::Stdin::Pty,
+        },
+        start: ap.start.iter().map(|a| instantiate(a, instance)).collect(),
+        stop: ap.stop.iter().map(|a| instantiate(a, instance)).collect(),
+        healthchecks,
+        limits,
+        cgroup,
+        oom_score_adj,
+        oom_kills: Default::default(),
+        scheduling,
+        accounting: Default::default(),
+        namespaces,
+        sandbox,
+        privileges,
+        syscall_filter,
+        umask,
+        sockets: app_sockets,
+        on_demand,
+        hooks,
+        success_exit_status,
+        restart_prevent_exit_status,
+        stdout,
+        stderr,
+        capture,
+        /* TODO: check requires are valid */
+        requires: ap
+            .requires
+            .iter()
+            .map(|name| match config.application.get(name) {
+                Some(required) => instances(required)
+                    .map(|is| is.iter().map(|&i| instance_id(name, i)).collect()),
+                None => Ok(vec![name.to_owned()]),
+            }).collect::<Result<Vec<_>, _>>()?
+            .concat(),
+        state: AppState::Idle,
+        hook_failed: false,
+        restart_prevented: false,
+        stop_pending: false,
+    })
+}
+
+/// The instances of an application. `None` is the only instance of an
+/// application without `instances`.
+fn instances(ap: &Application) -> Result<Vec<Option<u32>>, String> {
+    match ap.instances {
+        None => Ok(vec![None]),
+        Some(0) => Err("Invalid instances (0)".to_owned()),
+        Some(n) => Ok((1..=n).map(Some).collect()),
+    }
+}
+
+fn instance_id(name: &str, instance: Option<u32>) -> String {
+    match instance {
+        Some(i) => format!("{}@{}", name, i),
+        None => name.to_owned(),
+    }
+}
+
+/// Substitutes an instance's number for `%i` in `s`. `%%` is a
+/// literal `%`.
+fn instantiate(s: &str, instance: Option<u32>) -> String {
+    match instance {
+        Some(i) => s
+            .split("%%")
+            .map(|part| part.replace("%i", &i.to_string()))
+            .collect::<Vec<_>>()
+            .join("%"),
+        None => s.to_owned(),
+    }
+}
+
+fn instantiate_destinations(destinations: &mut [stream::Destination], instance: Option<u32>) {
+    destinations.iter_mut().for_each(|d| {
+        if let stream::Stream::File { ref mut filename, .. } = d.stream {
+            *filename = instantiate(filename, instance);
+        }
+    });
+}
+
 fn read_env_file(filename: &str) -> Result<HashMap<String, String>, String> {
     fs::read_to_string(filename)
         .map_err(|e| format!("Cant't read env_file {}: {:?}", filename, e))
@@ -334,58 +766,622 @@ fn mk_healthcheck(params: &str) -> Result<HealthCheck, String> {
     }
 }
 
-fn get_limits(configs: &HashMap<String, Limits>, limits: &[String]) -> Result<Vec<RLimit>, String> {
-    let min = |a, b| match (a, b) {
-        (x, Limit::Infinity) => x,
-        (Limit::Num(x), Limit::Num(y)) if x < y => Limit::Num(x),
+fn get_limits(
+    configs: &HashMap<String, Limits>,
+    limits: &[String],
+) -> Result<(Vec<RLimit>, Vec<Control>), String> {
+    let min = |a: Option<Limit>, b: Option<Limit>| match (a, b) {
+        (x, None) | (x, Some(Limit::Infinity)) => x.or(b),
+        (Some(Limit::Num(x)), Some(Limit::Num(y))) if x < y => a,
         (_, y) => y,
     };
 
-    let mut procs = Limit::Infinity;
-    let mut mem = Limit::Infinity;
-    let mut files = Limit::Infinity;
+    let mut rlimits: Vec<RLimit> = Vec::new();
+    let mut controls: Vec<Control> = Vec::new();
 
     for name in limits.iter() {
         match configs.get(name) {
             Some(config) => {
                 for (k, v) in config.iter() {
-                    match k.as_ref() {
-                        "max_procs" => procs = min(procs, Limit::Num(*v)),
-                        "max_mem" => mem = min(mem, Limit::Num(*v * 1024 * 1024)),
-                        "max_files" => files = min(files, Limit::Num(*v)),
-                        _ => return Err(format!("No such limit ({}).", k)),
+                    if let Some(control) = mk_control(k, v)? {
+                        match controls.iter().position(|c| c.overlaps(&control)) {
+                            Some(i) => controls[i] = controls[i].lower(&control),
+                            None => controls.push(control),
+                        }
+                        continue;
+                    }
+                    let (resource, soft, hard) = mk_limit(k, v)?;
+                    if let (Some(Limit::Num(s)), Some(Limit::Num(h))) = (soft, hard) {
+                        if s > h {
+                            return Err(format!("Soft limit exceeds hard limit ({})", k));
+                        }
+                    }
+                    match rlimits.iter().position(|l| l.resource == resource) {
+                        Some(i) => {
+                            let rlimit = &mut rlimits[i];
+                            rlimit.soft = min(rlimit.soft, soft);
+                            rlimit.hard = min(rlimit.hard, hard);
+                        }
+                        None => rlimits.push(RLimit {
+                            resource,
+                            soft,
+                            hard,
+                        }),
                     }
                 }
             }
             None => return Err(format!("No such limits \"{}\"", name)),
         }
     }
-    Ok(vec![
-        RLimit::Procs(procs),
-        RLimit::Memory(mem),
-        RLimit::Files(files),
-    ])
+    // a soft limit from one set mustn't exceed a hard limit from another
+    for rlimit in &mut rlimits {
+        if rlimit.soft.is_some() {
+            rlimit.soft = min(rlimit.soft, rlimit.hard);
+        }
+    }
+    Ok((rlimits, controls))
 }
 
-fn mk_stream(stream: &Stream) -> Result<stream::Stream, String> {
-    match stream {
-        Stream::File(filename) => Ok(stream::Stream::File {
-            filename: filename.to_owned(),
+/// Makes a cgroup control from a `limits` entry or returns `None` if
+/// `name` isn't a cgroup control.
+fn mk_control(name: &str, value: &LimitValue) -> Result<Option<Control>, String> {
+    const CONTROLS: &[&str] = &[
+        "memory_max",
+        "memory_high",
+        "cpu_max",
+        "cpu_weight",
+        "pids_max",
+        "io_max",
+    ];
+    if !CONTROLS.contains(&name) {
+        return Ok(None);
+    }
+    let value = match value {
+        LimitValue::Both(s) => s.trim(),
+        LimitValue::Split { .. } => return Err(format!("{} takes a single value", name)),
+    };
+    let bad = || format!("Invalid {} value ({})", name, value);
+    match name {
+        "memory_max" => limit::parse_limit(value, Unit::Bytes).map(Control::MemoryMax),
+        "memory_high" => limit::parse_limit(value, Unit::Bytes).map(Control::MemoryHigh),
+        "pids_max" => limit::parse_limit(value, Unit::Count).map(Control::PidsMax),
+        "cpu_max" => match value {
+            "infinity" => Ok(Control::CpuMax(Limit::Infinity)),
+            // a percentage of one CPU's time
+            v if v.ends_with('%') => v[..v.len() - 1]
+                .parse::<u64>()
+                .ok()
+                .filter(|&p| p > 0)
+                .map(|p| Control::CpuMax(Limit::Num(p * 1000)))
+                .ok_or_else(bad),
+            _ => Err(bad()),
+        },
+        "cpu_weight" => value
+            .parse::<u64>()
+            .ok()
+            .filter(|w| (1..=10_000).contains(w))
+            .map(Control::CpuWeight)
+            .ok_or_else(bad),
+        _ => {
+            // "MAJ:MIN rbps=1M wiops=100"
+            let mut parts = value.split_whitespace();
+            let device = parts.next().unwrap_or("");
+            let numbers = device.split(':').collect::<Vec<_>>();
+            if numbers.len() != 2 || numbers.iter().any(|n| n.parse::<u32>().is_err()) {
+                return Err(bad());
+            }
+            parts
+                .map(|part| {
+                    let kv = part.splitn(2, '=').collect::<Vec<_>>();
+                    let unit = match kv[0] {
+                        "rbps" | "wbps" => Unit::Bytes,
+                        "riops" | "wiops" => Unit::Count,
+                        _ => return Err(bad()),
+                    };
+                    match kv.get(1) {
+                        Some(v) => Ok((kv[0].to_owned(), limit::parse_limit(v, unit)?)),
+                        None => Err(bad()),
+                    }
+                }).collect::<Result<Vec<_>, String>>()
+                .and_then(|settings| {
+                    if settings.is_empty() {
+                        Err(bad())
+                    } else {
+                        Ok(Control::IoMax(device.to_owned(), settings))
+                    }
+                })
+        }
+    }.map(Some)
+}
+
+fn mk_limit(name: &str, value: &LimitValue) -> Result<(Resource, Option<Limit>, Option<Limit>), String> {
+    // max_procs, max_mem and max_files are the original names and
+    // max_mem is in megabytes unless a unit is given
+    let (resource, scale) = match name {
+        "max_procs" => (Resource::Nproc, 1),
+        "max_mem" => (Resource::As, 1024 * 1024),
+        "max_files" => (Resource::Nofile, 1),
+        _ => (
+            Resource::from_name(name).ok_or_else(|| format!("No such limit ({}).", name))?,
+            1,
+        ),
+    };
+    let parse = |s: &Option<String>| match s {
+        Some(s) => limit::parse_limit(s, resource.unit())
+            .map(|l| match l {
+                Limit::Num(n) if s.trim().chars().all(|c| c.is_ascii_digit()) => {
+                    Limit::Num(n.saturating_mul(scale))
+                }
+                l => l,
+            }).map(Some),
+        None => Ok(None),
+    };
+    match value {
+        LimitValue::Both(s) => {
+            let limit = parse(&Some(s.to_owned()))?;
+            Ok((resource, limit, limit))
+        }
+        LimitValue::Split { soft, hard } => Ok((resource, parse(soft)?, parse(hard)?)),
+    }
+}
+
+fn mk_scheduling(ap: &Application) -> Result<sched::Scheduling, String> {
+    let nice = match ap.nice {
+        Some(nice) if !(-20..=19).contains(&nice) => {
+            return Err(format!("Invalid nice value ({})", nice))
+        }
+        nice => nice,
+    };
+    let realtime = |p: u32| match p {
+        1..=99 => Ok(p),
+        _ => Err(format!("Invalid real-time priority ({})", p)),
+    };
+    let level = |l: u32| match l {
+        0..=7 => Ok(l),
+        _ => Err(format!("Invalid ioprio level ({})", l)),
+    };
+    Ok(sched::Scheduling {
+        nice,
+        cpu_affinity: match ap.cpu_affinity.as_ref() {
+            Some(cpus) => Some(sched::parse_cpu_list(cpus)?),
+            None => None,
+        },
+        policy: match ap.sched_policy.as_ref() {
+            Some(SchedPolicy::Other) => Some(sched::Policy::Other),
+            Some(SchedPolicy::Batch) => Some(sched::Policy::Batch),
+            Some(SchedPolicy::Idle) => Some(sched::Policy::Idle),
+            Some(SchedPolicy::Fifo(p)) => Some(sched::Policy::Fifo(realtime(*p)?)),
+            Some(SchedPolicy::Rr(p)) => Some(sched::Policy::Rr(realtime(*p)?)),
+            None => None,
+        },
+        ioprio: match ap.ioprio.as_ref() {
+            Some(IoPriority::Rt(l)) => Some(sched::IoPriority::Realtime(level(*l)?)),
+            Some(IoPriority::Be(l)) => Some(sched::IoPriority::BestEffort(level(*l)?)),
+            Some(IoPriority::Idle) => Some(sched::IoPriority::Idle),
+            None => None,
+        },
+    })
+}
+
+fn mk_namespaces(namespaces: &[Namespace]) -> Result<namespace::Namespaces, String> {
+    namespaces
+        .iter()
+        .try_fold(namespace::Namespaces::default(), |mut ns, n| {
+            match n {
+                Namespace::Mount => ns.mount = true,
+                Namespace::Pid => ns.pid = true,
+                Namespace::Net => ns.net = true,
+                Namespace::Ipc => ns.ipc = true,
+                Namespace::Uts => ns.uts = true,
+                Namespace::User(user) => {
+                    // by default riffol's user and group are root
+                    // inside the namespace
+                    let map = |lines: &[String], id: u32| match lines.len() {
+                        0 => Ok(format!("0 {} 1", id)),
+                        _ => namespace::parse_id_map(lines),
+                    };
+                    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+                    ns.user = Some(namespace::UserMaps {
+                        uid_map: map(&user.uid_map, uid)?,
+                        gid_map: map(&user.gid_map, gid)?,
+                    })
+                }
+            }
+            Ok(ns)
+        })
+}
+
+/// Builds an application's sandbox. Paths, including the working
+/// directory, are within `root_directory` if there is one.
+fn mk_sandbox(ap: &Application, dir: &str) -> Result<sandbox::Sandbox, String> {
+    let root = match ap.root_directory.as_ref() {
+        Some(root) if !root.starts_with('/') => {
+            return Err(format!("Invalid root_directory ({})", root))
+        }
+        Some(root) => root.trim_end_matches('/'),
+        None => "",
+    };
+    let path = |p: &str| {
+        if !p.starts_with('/') {
+            return Err(format!("Invalid path ({}), paths must be absolute", p));
+        }
+        CString::new(format!("{}{}", root, p)).map_err(|_| format!("Invalid path ({})", p))
+    };
+    let paths = |ps: &[String]| ps.iter().map(|p| path(p)).collect::<Result<Vec<_>, _>>();
+    Ok(sandbox::Sandbox {
+        root: match ap.root_directory.as_ref() {
+            Some(r) => Some(sandbox::Root {
+                path: CString::new(r.to_owned()).map_err(|_| format!("Invalid path ({})", r))?,
+                dir: CString::new(dir.to_owned()).map_err(|_| format!("Invalid path ({})", dir))?,
+            }),
+            None => None,
+        },
+        read_write_paths: paths(&ap.read_write_paths)?,
+        read_only_paths: paths(&ap.read_only_paths)?,
+        inaccessible_paths: paths(&ap.inaccessible_paths)?,
+        private_tmp: match ap.private_tmp {
+            Some(Flag::True) => {
+                let mut dirs = vec![path("/tmp")?];
+                if Path::new(&format!("{}/var/tmp", root)).is_dir() {
+                    dirs.push(path("/var/tmp")?);
+                }
+                dirs
+            }
+            _ => Vec::new(),
+        },
+    })
+}
+
+fn mk_privileges(ap: &Application) -> Result<privilege::Privileges, String> {
+    // otherwise the application keeps riffol's groups
+    if ap.uid.is_some() && ap.gid.is_none() {
+        return Err("A uid needs a gid too".to_owned());
+    }
+    Ok(privilege::Privileges {
+        uid: ap.uid,
+        gid: ap.gid,
+        capabilities: match ap.capabilities.len() {
+            0 => None,
+            _ => Some(privilege::parse_capabilities(&ap.capabilities)?),
+        },
+        bounding_set: match ap.capability_bounding_set.as_ref() {
+            Some(names) => Some(privilege::parse_capabilities(names)?),
+            None => None,
+        },
+        no_new_privileges: matches!(ap.no_new_privileges, Some(Flag::True)),
+    })
+}
+
+fn mk_schedule(ap: &Application) -> Result<schedule::Schedule, String> {
+    let timing = match (ap.schedule.as_ref(), ap.interval) {
+        (Some(expr), None) => {
+            let cron = schedule::Cron::parse(expr)?;
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(|e| e.to_string())?;
+            if cron.next_after(now.as_secs() as libc::time_t).is_none() {
+                return Err(format!("Cron expression never matches ({})", expr));
+            }
+            schedule::Timing::Cron(cron)
+        }
+        (None, Some(0)) => return Err("Invalid interval (0)".to_owned()),
+        (None, Some(secs)) => schedule::Timing::Interval(Duration::from_secs(secs)),
+        _ => return Err("A scheduled application needs one of schedule or interval".to_owned()),
+    };
+    Ok(schedule::Schedule {
+        timing,
+        overlap: match ap.overlap {
+            None | Some(Overlap::Skip) => schedule::Overlap::Skip,
+            Some(Overlap::Queue) => schedule::Overlap::Queue,
+            Some(Overlap::Kill) => schedule::Overlap::Kill,
+        },
+    })
+}
+
+fn mk_hooks(ap: &Application, instance: Option<u32>) -> Result<application::Hooks, String> {
+    // a command starting with "-" can fail without consequence
+    let hooks = |commands: &[Vec<String>]| {
+        commands
+            .iter()
+            .map(|command| {
+                let mut args = command
+                    .iter()
+                    .map(|a| instantiate(a, instance))
+                    .collect::<Vec<_>>();
+                let ignore_failure = args.first().filter(|a| a.starts_with('-')).is_some();
+                if ignore_failure {
+                    args[0].remove(0);
+                }
+                match args.first() {
+                    Some(a) if !a.is_empty() => Ok(application::Hook {
+                        args,
+                        ignore_failure,
+                    }),
+                    _ => Err("Empty hook command".to_owned()),
+                }
+            }).collect::<Result<Vec<_>, String>>()
+    };
+    Ok(application::Hooks {
+        start_pre: hooks(&ap.exec_start_pre)?,
+        start_post: hooks(&ap.exec_start_post)?,
+        stop_pre: hooks(&ap.exec_stop_pre)?,
+        stop_post: hooks(&ap.exec_stop_post)?,
+        timeout: Duration::from_secs(ap.hook_timeout.unwrap_or(30)),
+    })
+}
+
+fn mk_exit_statuses(statuses: &[String]) -> Result<application::ExitStatuses, String> {
+    statuses
+        .iter()
+        .try_fold(application::ExitStatuses::default(), |mut acc, s| {
+            match s.parse::<u8>() {
+                Ok(code) => acc.codes.push(i32::from(code)),
+                Err(_) => match signal::parse_signal(s) {
+                    Some(sig) => acc.signals.push(sig),
+                    None => return Err(format!("Invalid exit status ({})", s)),
+                },
+            }
+            Ok(acc)
+        })
+}
+
+fn mk_syscall_filter(filter: &SyscallFilter) -> Result<seccomp::Filter, String> {
+    let action = match filter.action {
+        None | Some(FilterAction::Kill) => seccomp::Action::Kill,
+        Some(FilterAction::Errno(errno)) if errno > 0 && errno < 4096 => {
+            seccomp::Action::Errno(errno)
+        }
+        Some(FilterAction::Errno(errno)) => {
+            return Err(format!("Invalid syscall_filter errno ({})", errno))
+        }
+    };
+    match (filter.allow.as_ref(), filter.deny.as_ref()) {
+        (Some(allow), None) => {
+            // the calls every program needs to start are always allowed
+            let mut names = allow.clone();
+            names.push("@default".to_owned());
+            seccomp::Filter::new(true, &seccomp::parse_syscalls(&names)?, action)
+        }
+        (None, Some(deny)) => seccomp::Filter::new(false, &seccomp::parse_syscalls(deny)?, action),
+        _ => Err("syscall_filter needs one of allow or deny".to_owned()),
+    }
+}
+
+/// Finds the socket `name`, binding it if it isn't already
+fn get_socket(
+    configs: &HashMap<String, Socket>,
+    sockets: &mut HashMap<String, Arc<socket::Socket>>,
+    name: &str,
+) -> Result<Arc<socket::Socket>, String> {
+    if let Some(socket) = sockets.get(name) {
+        return Ok(socket.clone());
+    }
+    let config = configs
+        .get(name)
+        .ok_or_else(|| format!("No such socket \"{}\"", name))?;
+    let socket = Arc::new(mk_socket(name, config)?);
+    sockets.insert(name.to_owned(), socket.clone());
+    Ok(socket)
+}
+
+fn mk_socket(name: &str, config: &Socket) -> Result<socket::Socket, String> {
+    let listen = &config.listen;
+    let invalid = || format!("Invalid socket address ({})", listen);
+    let address = match (listen.strip_prefix("tcp://"), listen.strip_prefix("unix://")) {
+        (Some(addr), _) => socket::Address::Tcp(SocketAddr::from_str(addr).map_err(|_| invalid())?),
+        (_, Some(path)) if path.starts_with('/') => socket::Address::Unix(Path::new(path).to_path_buf()),
+        _ => return Err(invalid()),
+    };
+    let path = match &address {
+        socket::Address::Unix(path) => Some(path),
+        socket::Address::Tcp(_) if config.mode.is_some() || config.owner.is_some() => {
+            return Err(format!("Socket {} can only have a mode or owner if it's a unix socket", name))
+        }
+        _ => None,
+    };
+    let mode = match config.mode.as_ref() {
+        Some(mode) => Some(parse_mode(mode, 0o7777, "mode")?),
+        None => None,
+    };
+    let owner = match config.owner.as_ref() {
+        Some(owner) => Some(parse_owner(owner)?),
+        None => None,
+    };
+    let socket = socket::Socket::bind(name, &address)
+        .map_err(|e| format!("Can't bind socket {} ({}): {}", name, listen, e))?;
+    if let Some(path) = path {
+        if let Some(mode) = mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))
+                .map_err(|e| format!("Can't set mode of socket {}: {}", name, e))?;
+        }
+        if let Some((uid, gid)) = owner {
+            let cpath = CString::new(path.as_os_str().as_bytes())
+                .map_err(|_| invalid())?;
+            if unsafe { libc::chown(cpath.as_ptr(), uid, gid) } == -1 {
+                return Err(format!(
+                    "Can't set owner of socket {}: {}",
+                    name,
+                    ::std::io::Error::last_os_error()
+                ));
+            }
+        }
+    }
+    Ok(socket)
+}
+
+/// Parses an octal file mode or umask no greater than `max`
+fn parse_mode(mode: &str, max: u32, name: &str) -> Result<u32, String> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(m) if m <= max => Ok(m),
+        _ => Err(format!("Invalid {} ({})", name, mode)),
+    }
+}
+
+/// Parses `user[:group]`, by name or id, into a uid and gid. The gid
+/// is `!0`, meaning unchanged, if no group is given.
+fn parse_owner(owner: &str) -> Result<(libc::uid_t, libc::gid_t), String> {
+    let err = || format!("Invalid owner ({})", owner);
+    let mut parts = owner.splitn(2, ':');
+    let user = parts.next().unwrap_or("");
+    let uid = match user.parse::<libc::uid_t>() {
+        Ok(uid) => uid,
+        Err(_) => {
+            let name = CString::new(user).map_err(|_| err())?;
+            let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+            if passwd.is_null() {
+                return Err(format!("No such user \"{}\"", user));
+            }
+            unsafe { (*passwd).pw_uid }
+        }
+    };
+    let gid = match parts.next() {
+        None => !0,
+        Some(group) => match group.parse::<libc::gid_t>() {
+            Ok(gid) => gid,
+            Err(_) => {
+                let name = CString::new(group).map_err(|_| err())?;
+                let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+                if entry.is_null() {
+                    return Err(format!("No such group \"{}\"", group));
+                }
+                unsafe { (*entry).gr_gid }
+            }
+        },
+    };
+    Ok((uid, gid))
+}
+
+fn mk_destinations(
+    destinations: &Destinations,
+    umask: Option<u32>,
+) -> Result<Vec<stream::Destination>, String> {
+    destinations
+        .0
+        .iter()
+        .map(|d| mk_destination(d, umask))
+        .collect()
+}
+
+fn mk_destination(
+    destination: &Destination,
+    umask: Option<u32>,
+) -> Result<stream::Destination, String> {
+    let stream = mk_stream(&destination.stream, umask)?;
+    let timestamp = match (destination.timestamp.as_ref(), &stream) {
+        (None, _) => None,
+        (Some(t), stream::Stream::File { .. }) | (Some(t), stream::Stream::Console) => Some(
+            CString::new(t.to_owned()).map_err(|_| format!("Invalid timestamp ({})", t))?,
+        ),
+        (Some(_), _) => {
+            return Err("timestamp is only supported by file and console streams".to_owned())
+        }
+    };
+    Ok(stream::Destination {
+        stream,
+        timestamp,
+        rate_limit: match destination.rate_limit.as_ref() {
+            Some(r) => Some(mk_rate_limit(r)?),
+            None => None,
+        },
+        multiline: match destination.multiline.as_ref() {
+            Some(m) => Some(mk_multiline(m)?),
+            None => None,
+        },
+        queue: destination
+            .queue
+            .as_ref()
+            .map_or_else(|| Ok(stream::Queue::default()), mk_queue)?,
+    })
+}
+
+fn mk_rate_limit(limit: &RateLimit) -> Result<stream::RateLimit, String> {
+    match (limit.lines, limit.bytes, limit.interval) {
+        (None, None, _) => Err("rate_limit needs lines and/or bytes".to_owned()),
+        (Some(0), _, _) | (_, Some(0), _) | (_, _, Some(0)) => {
+            Err("rate_limit values must be greater than 0".to_owned())
+        }
+        (lines, bytes, interval) => Ok(stream::RateLimit {
+            lines,
+            bytes,
+            interval: Duration::from_secs(interval.unwrap_or(1)),
+            burst: limit.burst,
         }),
+    }
+}
+
+fn mk_queue(queue: &Queue) -> Result<stream::Queue, String> {
+    let default = stream::Queue::default();
+    Ok(stream::Queue {
+        size: match queue.size {
+            Some(0) => return Err("Invalid queue size (0)".to_owned()),
+            Some(n) => n as usize,
+            None => default.size,
+        },
+        overflow: queue.overflow.as_ref().map_or_else(
+            || Ok(default.overflow),
+            |s| match s.as_ref() {
+                "drop_oldest" => Ok(stream::Overflow::DropOldest),
+                "drop_newest" => Ok(stream::Overflow::DropNewest),
+                "block" => Ok(stream::Overflow::Block),
+                _ => Err(format!("Invalid queue overflow ({})", s)),
+            },
+        )?,
+    })
+}
+
+fn mk_multiline(multiline: &Multiline) -> Result<stream::Multiline, String> {
+    Ok(stream::Multiline {
+        continuation: match multiline.pattern.as_ref() {
+            Some(p) => stream::Continuation::Pattern(
+                Regex::new(p).map_err(|e| format!("Invalid multiline pattern ({})", e))?,
+            ),
+            None => stream::Continuation::Indented,
+        },
+        timeout: Duration::from_millis(multiline.timeout.unwrap_or(1000)),
+    })
+}
+
+/// Makes a stream. Files created for `file` streams get permissions
+/// masked by the application's `umask`, if it has one, rather than
+/// Riffol's.
+fn mk_stream(stream: &Stream, umask: Option<u32>) -> Result<stream::Stream, String> {
+    match stream {
+        Stream::Console => Ok(stream::Stream::Console),
+        Stream::File(filename, options) => {
+            let mode = match options.as_ref().and_then(|o| o.mode.as_ref()) {
+                Some(mode) => Some(parse_mode(mode, 0o7777, "mode")?),
+                None => None,
+            };
+            let owner = match options.as_ref().and_then(|o| o.owner.as_ref()) {
+                Some(owner) => Some(parse_owner(owner)?),
+                None => None,
+            };
+            Ok(stream::Stream::File {
+                filename: filename.to_owned(),
+                mode: match (mode, umask) {
+                    (None, None) => None,
+                    (mode, umask) => Some(mode.unwrap_or(0o666) & !umask.unwrap_or(0)),
+                },
+                owner,
+            })
+        }
         Stream::Syslog {
             socket,
             facility,
             severity,
+            severity_rules,
         } => Ok(stream::Stream::Syslog {
             address: stream::Address::Unix(socket.to_owned()),
             facility: config_to_syslog_facility(facility),
             severity: config_to_syslog_severity(severity),
+            rules: mk_severity_rules(severity_rules)?,
         }),
         Stream::RSyslog {
             server,
             local,
             facility: f,
             severity: s,
+            severity_rules,
         } => Ok(stream::Stream::Syslog {
             address: {
                 if let Ok(server) = SocketAddr::from_str(server) {
@@ -402,10 +1398,67 @@ fn mk_stream(stream: &Stream) -> Result<stream::Stream, String> {
             },
             facility: config_to_syslog_facility(f),
             severity: config_to_syslog_severity(s),
+            rules: mk_severity_rules(severity_rules)?,
+        }),
+        Stream::Journal {
+            socket,
+            identifier,
+            facility,
+            severity,
+            severity_rules,
+        } => Ok(stream::Stream::Journal {
+            socket: socket
+                .to_owned()
+                .unwrap_or_else(|| stream::JOURNAL_SOCKET.to_owned()),
+            identifier: identifier.to_owned(),
+            facility: config_to_syslog_facility(facility),
+            severity: config_to_syslog_severity(severity),
+            rules: mk_severity_rules(severity_rules)?,
         }),
     }
 }
 
+fn mk_severity_rules(rules: &[SeverityRule]) -> Result<Vec<stream::SeverityRule>, String> {
+    rules
+        .iter()
+        .map(|rule| match rule {
+            SeverityRule::Prefix => Ok(stream::SeverityRule::Prefix),
+            SeverityRule::Key(key) => Ok(stream::SeverityRule::Key(key.to_owned())),
+            SeverityRule::Pattern { regex, severity } => Regex::new(regex)
+                .map(|r| stream::SeverityRule::Pattern(r, syslog_severity(severity)))
+                .map_err(|e| format!("Invalid severity pattern ({})", e)),
+        }).collect()
+}
+
+fn mk_capture(capture: &Capture) -> Result<stream::Capture, String> {
+    let default = stream::Capture::default();
+    Ok(stream::Capture {
+        max_line: match capture.max_line {
+            Some(0) => return Err("Invalid capture max_line (0)".to_owned()),
+            Some(n) => n as usize,
+            None => default.max_line,
+        },
+        overlong: capture
+            .overlong
+            .as_ref()
+            .map(|o| match o {
+                Overlong::Split => stream::Overlong::Split,
+                Overlong::Truncate => stream::Overlong::Truncate,
+            }).unwrap_or(default.overlong),
+        encoding: capture
+            .encoding
+            .as_ref()
+            .map(|e| match e {
+                Encoding::Lossy => stream::Encoding::Lossy,
+                Encoding::Escape => stream::Encoding::Escape,
+            }).unwrap_or(default.encoding),
+        history: capture
+            .history
+            .map(|n| n as usize)
+            .unwrap_or(default.history),
+    })
+}
+
 fn config_to_syslog_facility(f: &Option<SyslogFacility>) -> syslog::Facility {
     f.as_ref()
         .map(|f| match f {
@@ -434,23 +1487,32 @@ fn config_to_syslog_facility(f: &Option<SyslogFacility>) -> syslog::Facility {
 
 fn config_to_syslog_severity(s: &Option<SyslogSeverity>) -> u32 {
     s.as_ref()
-        .map(|s| match s {
-            SyslogSeverity::EMERG => syslog::Severity::LOG_EMERG,
-            SyslogSeverity::ALERT => syslog::Severity::LOG_ALERT,
-            SyslogSeverity::CRIT => syslog::Severity::LOG_CRIT,
-            SyslogSeverity::ERR => syslog::Severity::LOG_ERR,
-            SyslogSeverity::WARNING => syslog::Severity::LOG_WARNING,
-            SyslogSeverity::NOTICE => syslog::Severity::LOG_NOTICE,
-            SyslogSeverity::INFO => syslog::Severity::LOG_INFO,
-            SyslogSeverity::DEBUG => syslog::Severity::LOG_DEBUG,
-        }).unwrap_or(syslog::Severity::LOG_DEBUG) as u32
+        .map(syslog_severity)
+        .unwrap_or(syslog::Severity::LOG_DEBUG as u32)
+}
+
+fn syslog_severity(s: &SyslogSeverity) -> u32 {
+    (match s {
+        SyslogSeverity::EMERG => syslog::Severity::LOG_EMERG,
+        SyslogSeverity::ALERT => syslog::Severity::LOG_ALERT,
+        SyslogSeverity::CRIT => syslog::Severity::LOG_CRIT,
+        SyslogSeverity::ERR => syslog::Severity::LOG_ERR,
+        SyslogSeverity::WARNING => syslog::Severity::LOG_WARNING,
+        SyslogSeverity::NOTICE => syslog::Severity::LOG_NOTICE,
+        SyslogSeverity::INFO => syslog::Severity::LOG_INFO,
+        SyslogSeverity::DEBUG => syslog::Severity::LOG_DEBUG,
+    }) as u32
 }
 
 #[cfg(test)]
 mod tests {
-    use super::get_limits;
+    use super::{get_limits, instantiate, mk_control, parse_mode, parse_owner, LimitValue};
+    use cgroup::Control;
+    use limit::{Limit, Resource};
     use super::mk_healthcheck;
+    use super::{mk_multiline, Multiline};
     use std::collections::HashMap;
+    use stream::{Destination, Stream};
 
     #[test]
     fn test() {
@@ -461,6 +1523,30 @@ mod tests {
         let config = super::get_config(args);
         println!("{:?}", config);
         assert!(config.is_ok());
+        let apps = config.unwrap().applications;
+        let worker = apps.iter().find(|a| a.id == "worker@2").unwrap();
+        assert_eq!(worker.start[2], "802");
+        assert_eq!(worker.env["WORKER_ID"], "worker-2");
+        let db = apps.iter().find(|a| a.id == "db").unwrap();
+        assert_eq!(db.hooks.start_pre[1].args, ["/bin/rm", "/run/db/db.sock"]);
+        assert!(!db.hooks.start_pre[0].ignore_failure && db.hooks.start_pre[1].ignore_failure);
+        assert_eq!(db.success_exit_status.signals, [libc::SIGTERM]);
+        assert_eq!(db.success_exit_status.codes, [143]);
+        assert_eq!(db.restart_prevent_exit_status.codes, [3]);
+        // a list of destinations or a single one
+        let streams = db.stdout.iter().map(|d| &d.stream).collect::<Vec<_>>();
+        assert!(matches!(
+            streams[..],
+            [
+                Stream::File { .. },
+                Stream::Journal { .. },
+                Stream::Console
+            ]
+        ));
+        assert!(db.stdout[0].rate_limit.is_some() && db.stdout[1].rate_limit.is_none());
+        assert!(matches!(db.stderr[..], [Destination { stream: Stream::Syslog { .. }, .. }]));
+        let www = apps.iter().find(|a| a.id == "www").unwrap();
+        assert_eq!(www.requires, ["worker@1", "worker@2", "worker@3"]);
 
         // test mk_healthcheck
         assert!(mk_healthcheck("unknown").is_err());
@@ -477,16 +1563,103 @@ mod tests {
         assert!(mk_healthcheck("proc://").is_err());
         assert!(mk_healthcheck("proc://anything").is_ok());
 
+        // test mk_multiline
+        let multiline = |pattern: Option<&str>| Multiline {
+            pattern: pattern.map(|p| p.to_owned()),
+            timeout: None,
+        };
+        assert!(mk_multiline(&multiline(None)).is_ok());
+        assert!(mk_multiline(&multiline(Some("^\\s"))).is_ok());
+        assert!(mk_multiline(&multiline(Some("("))).is_err());
+
         // test get_limits
-        let limits: HashMap<String, u64> = [("max_procs".to_owned(), 64)].iter().cloned().collect();
-        let config: HashMap<String, HashMap<String, u64>> =
+        let both = |s: &str| LimitValue::Both(s.to_owned());
+        let limits: HashMap<String, LimitValue> =
+            [("max_procs".to_owned(), both("64"))].iter().cloned().collect();
+        let config: HashMap<String, HashMap<String, LimitValue>> =
             vec![("1".to_owned(), limits)].iter().cloned().collect();
         assert!(get_limits(&config, &vec!["2".to_owned()]).is_err());
         assert!(get_limits(&config, &vec!["1".to_owned()]).is_ok());
 
-        let limits: HashMap<String, u64> = [("nonono".to_owned(), 64)].iter().cloned().collect();
-        let config: HashMap<String, HashMap<String, u64>> =
+        let limits: HashMap<String, LimitValue> =
+            [("nonono".to_owned(), both("64"))].iter().cloned().collect();
+        let config: HashMap<String, HashMap<String, LimitValue>> =
             vec![("1".to_owned(), limits)].iter().cloned().collect();
         assert!(get_limits(&config, &vec!["1".to_owned()]).is_err());
+
+        let split = |soft: &str, hard: &str| LimitValue::Split {
+            soft: Some(soft.to_owned()),
+            hard: Some(hard.to_owned()),
+        };
+        let config: HashMap<String, HashMap<String, LimitValue>> = vec![
+            (
+                "1".to_owned(),
+                vec![
+                    ("max_mem".to_owned(), both("1024")),
+                    ("nofile".to_owned(), split("1024", "4096")),
+                ].into_iter()
+                .collect(),
+            ),
+            (
+                "2".to_owned(),
+                vec![
+                    ("as".to_owned(), both("512M")),
+                    ("nofile".to_owned(), split("2048", "infinity")),
+                ].into_iter()
+                .collect(),
+            ),
+            ("3".to_owned(), vec![("cpu".to_owned(), split("2h", "1h"))].into_iter().collect()),
+        ].into_iter()
+        .collect();
+        let (limits, _) = get_limits(&config, &["1".to_owned(), "2".to_owned()]).unwrap();
+        let find = |r| limits.iter().find(|l| l.resource == r).unwrap();
+        assert_eq!(find(Resource::As).soft, Some(Limit::Num(512 << 20)));
+        assert_eq!(find(Resource::Nofile).soft, Some(Limit::Num(1024)));
+        assert_eq!(find(Resource::Nofile).hard, Some(Limit::Num(4096)));
+        assert!(get_limits(&config, &["3".to_owned()]).is_err());
+
+        // test instantiate
+        assert_eq!(instantiate("/run/app%i.pid", Some(2)), "/run/app2.pid");
+        assert_eq!(instantiate("%%i-%i%%", Some(3)), "%i-3%");
+        assert_eq!(instantiate("50%i", None), "50%i");
+
+        // test parse_mode and parse_owner
+        assert_eq!(parse_mode("027", 0o777, "umask"), Ok(0o27));
+        assert_eq!(parse_mode("0640", 0o7777, "mode"), Ok(0o640));
+        assert!(parse_mode("1000", 0o777, "umask").is_err());
+        assert!(parse_mode("9", 0o777, "umask").is_err());
+        assert_eq!(parse_owner("0"), Ok((0, !0)));
+        assert_eq!(parse_owner("root:0"), Ok((0, 0)));
+        assert_eq!(parse_owner("0:root"), Ok((0, 0)));
+        assert!(parse_owner("no-such-user").is_err());
+        assert!(parse_owner("0:no-such-group").is_err());
+
+        // test mk_control
+        let control = |k: &str, v: &str| mk_control(k, &both(v));
+        assert_eq!(control("nofile", "1"), Ok(None));
+        assert_eq!(
+            control("memory_max", "1G"),
+            Ok(Some(Control::MemoryMax(Limit::Num(1 << 30))))
+        );
+        assert_eq!(
+            control("cpu_max", "150%"),
+            Ok(Some(Control::CpuMax(Limit::Num(150_000))))
+        );
+        assert!(control("cpu_max", "0%").is_err());
+        assert!(control("cpu_weight", "0").is_err());
+        assert_eq!(
+            control("io_max", "8:0 rbps=1M wiops=100"),
+            Ok(Some(Control::IoMax(
+                "8:0".to_owned(),
+                vec![
+                    ("rbps".to_owned(), Limit::Num(1 << 20)),
+                    ("wiops".to_owned(), Limit::Num(100))
+                ]
+            )))
+        );
+        assert!(control("io_max", "8:0").is_err());
+        assert!(control("io_max", "sda rbps=1").is_err());
+        assert!(control("io_max", "8:0 rbps=1h").is_err());
+        assert!(mk_control("memory_max", &split("1", "2")).is_err());
     }
 }
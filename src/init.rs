@@ -24,35 +24,89 @@
 use application::Application;
 use crossbeam_channel as cc;
 use libc;
+use schedule::Overlap;
 use signal_hook;
+use socket;
 use std::collections::HashMap;
 use std::time::{Duration, Instant};
 use stream;
+use usage::Usage;
 
 struct InitApp {
     inner: Application,
     needs_stop: bool,
     kill_time: Option<Instant>,
+    /// The hook command `kill_time` was set for
+    timed_hook: Option<u32>,
     start_time: Option<Instant>,
     depends: Vec<usize>,
     rdepends: Vec<usize>,
+    /// The number of times in a row the application has been killed
+    /// by the OOM killer
+    oom_streak: u32,
+    /// Whether an on-demand application has had a connection
+    activated: bool,
+    /// Resumes watching an on-demand application's sockets
+    rearm: Option<cc::Sender<()>>,
+    /// When a scheduled application is next due to run
+    next_run: Option<Instant>,
+    /// Whether a scheduled application is waiting to start a run
+    due: bool,
 }
 
 impl InitApp {
     fn new(app: Application) -> Self {
         Self {
+            next_run: app.schedule().and_then(|s| s.next_run(None)),
             inner: app,
             needs_stop: false,
             kill_time: None,
+            timed_hook: None,
             start_time: None,
             depends: Vec::new(),
             rdepends: Vec::new(),
+            oom_streak: 0,
+            activated: false,
+            rearm: None,
+            due: false,
         }
     }
+
+    /// Goes back to waiting for a connection before starting an
+    /// on-demand application
+    fn deactivate(&mut self) {
+        if let Some(rearm) = self.rearm.as_ref().filter(|_| self.activated) {
+            self.activated = false;
+            rearm.send(());
+        }
+    }
+
+    /// Sets the kill timer for the application's current state. A
+    /// hook command's deadline is set once, when it starts.
+    fn set_kill_time(&mut self) {
+        let hook_pid = self.inner.hook_pid();
+        if hook_pid.is_none() || hook_pid != self.timed_hook {
+            self.kill_time = self.inner.timeout().map(|t| Instant::now() + t);
+        }
+        self.timed_hook = hook_pid;
+    }
+
+    /// How long to wait before restarting the application, backing
+    /// off if it keeps running out of memory
+    fn restart_delay(&self) -> Duration {
+        Duration::from_secs(1 << self.oom_streak.min(6))
+    }
 }
 
+/// How often the resource use of running applications is sampled
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often applications only tracked by their cgroup are checked
+const CGROUP_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct Init {
     applications: Vec<InitApp>,
+    sample_time: Instant,
 }
 
 impl Init {
@@ -63,35 +117,51 @@ impl Init {
     ) {
         let mut apps = Self {
             applications: applications.drain(..).map(InitApp::new).collect(),
+            sample_time: Instant::now() + SAMPLE_INTERVAL,
         };
 
         apps.setup_dependencies();
 
-        let mut stream_handler = stream::Handler::new();
+        let (activate_send, activate_recv) = cc::unbounded();
+        apps.applications
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, app)| app.inner.on_demand)
+            .for_each(|(idx, app)| {
+                let fds = app.inner.sockets.iter().map(|s| s.fd()).collect();
+                app.rearm = Some(socket::watch(fds, idx, activate_send.clone()));
+            });
+
+        let stream_handler = stream::Handler::new();
 
         let mut shutdown = false;
         while !(shutdown && apps.all_stopped()) {
+            apps.check_cgroups(&stream_handler);
+            apps.do_samples();
             apps.do_kills();
-            apps.do_stops();
+            apps.do_stops(&stream_handler);
             if !shutdown {
-                apps.do_starts(&mut stream_handler);
+                apps.do_schedules(&stream_handler);
+                apps.do_starts(&stream_handler);
             }
 
             let timer = apps.get_next_timeout().map(cc::after);
             let mut select = cc::Select::new()
                 .recv(&sig_recv, |s| s.map(Event::Signal))
-                .recv(&fail_recv, |f| f.map(Event::Fail));
+                .recv(&fail_recv, |f| f.map(Event::Fail))
+                .recv(&activate_recv, |i| i.map(Event::Activate));
             if let Some(timer) = timer.as_ref() {
                 select = select.recv(timer, |t| t.map(|_| Event::Timer));
             }
 
             match select.wait() {
                 Some(Event::Signal(signal)) => {
-                    apps.handle_signal(signal);
+                    apps.handle_signal(signal, &stream_handler);
                     shutdown =
                         shutdown || signal == signal_hook::SIGTERM || signal == signal_hook::SIGINT;
                 }
                 Some(Event::Fail((group, msg))) => apps.handle_healthcheck_fail(&group, &msg),
+                Some(Event::Activate(idx)) => apps.handle_activate(idx),
                 Some(Event::Timer) => (),
                 None => unreachable!(),
             }
@@ -99,55 +169,114 @@ impl Init {
             enum Event {
                 Signal(i32),
                 Fail((String, String)),
+                Activate(usize),
                 Timer,
             }
         }
     }
 
-    fn handle_signal(&mut self, sig: i32) {
+    fn handle_signal(&mut self, sig: i32, stream_handler: &stream::Handler) {
         if sig == signal_hook::SIGCHLD {
-            let mut status: libc::c_int = 0;
-            let child = unsafe { libc::wait(&mut status) } as u32;
-            debug!("SIGCHLD received {} {}", child, status);
-
-            let index = self
-                .applications
-                .iter_mut()
-                .position(|app: &mut InitApp| app.inner.claim_child(child, status));
-
-            let mut stop_idx = None;
-            if let Some(idx) = index {
-                let app = &mut self.applications[idx];
-                // remove kill timer as process has died by some other means
-                app.kill_time = None;
-                if app.inner.is_dead() {
-                    // The application just died unexpectedly.  We
-                    // still need to run the stop command to
-                    // perform any cleanup.
-                    stop_idx = Some(idx);
-                } else if app.inner.is_runaway() {
-                    // The child was the stop process for an
-                    // application and the main process is still
-                    // active.  We set a kill timer in case the
-                    // applicatiion doesn't die naturally
-                    app.kill_time = Some(Instant::now() + Duration::from_secs(5));
-                } else if app.inner.is_idle() {
-                    // Application has gone idle so we can set a restart time
-                    app.start_time = Some(Instant::now() + Duration::from_secs(1));
+            // SIGCHLDs which arrive together are merged so every child
+            // which has exited is reaped
+            loop {
+                let mut status: libc::c_int = 0;
+                let mut rusage: libc::rusage = unsafe { ::std::mem::zeroed() };
+                let child = unsafe { libc::wait4(-1, &mut status, libc::WNOHANG, &mut rusage) };
+                if child <= 0 {
+                    break;
+                }
+                let child = child as u32;
+                debug!("SIGCHLD received {} {}", child, status);
+                let usage = Usage::from_rusage(&rusage);
+
+                let index = self
+                    .applications
+                    .iter_mut()
+                    .position(|app: &mut InitApp| {
+                        app.inner.claim_child(child, status, &usage, stream_handler)
+                    });
+
+                if let Some(idx) = index {
+                    self.handle_change(idx);
+                } else {
+                    info!("Reaped zombie with PID {}", child);
                 }
-            } else {
-                info!("Reaped zombie with PID {}", child);
             }
-
-            stop_idx.map_or((), |idx| self.schedule_stop(idx));
         } else if sig == signal_hook::SIGTERM || sig == signal_hook::SIGINT {
             debug!("Received termination signal ({})", sig);
             self.applications.iter_mut().for_each(|app| {
                 if !(app.inner.is_stopped()) {
                     app.needs_stop = true;
                 }
+                app.next_run = None;
             });
+        } else if sig == signal_hook::SIGUSR1 {
+            self.applications.iter_mut().for_each(|app| {
+                app.inner.sample_usage();
+                app.inner.report_usage();
+            });
+        } else if sig == signal_hook::SIGUSR2 {
+            self.applications
+                .iter()
+                .for_each(|app| app.inner.report_output(stream_handler));
+        }
+    }
+
+    /// Follows up on an application whose state has changed because
+    /// one of its processes exited
+    fn handle_change(&mut self, idx: usize) {
+        let mut stop_idx = None;
+        {
+            let app = &mut self.applications[idx];
+            // remove kill timer as process has died by some other
+            // means, unless it's for a hook command still running
+            let hook_pid = app.inner.hook_pid();
+            if hook_pid.is_none() || hook_pid != app.timed_hook {
+                app.kill_time = None;
+            }
+            match app.inner.take_oom_kill() {
+                Some(true) => app.oom_streak += 1,
+                Some(false) => app.oom_streak = 0,
+                None => (),
+            }
+            if app.inner.is_dead() || app.inner.take_hook_failure() {
+                // The application just died unexpectedly, or its
+                // exec_start_post command failed.  We still need to
+                // run the stop command to perform any cleanup.
+                stop_idx = Some(idx);
+            } else if app.inner.is_runaway() {
+                // The child was the stop process for an
+                // application and the main process is still
+                // active.  We set a kill timer in case the
+                // applicatiion doesn't die naturally
+                app.kill_time = Some(Instant::now() + Duration::from_secs(5));
+            } else if app.inner.timeout().is_some() {
+                // The application has moved on to or from a hook
+                // command which mustn't take too long
+                app.set_kill_time();
+            } else if app.inner.is_idle() {
+                // Application has gone idle so we can set a restart time
+                app.start_time = Some(Instant::now() + app.restart_delay());
+                app.deactivate();
+            }
         }
+
+        stop_idx.map_or((), |idx| self.schedule_stop(idx));
+    }
+
+    /// Checks applications which are only tracked by their cgroup
+    fn check_cgroups(&mut self, stream_handler: &stream::Handler) {
+        let changed = (0..self.applications.len())
+            .filter(|&idx| self.applications[idx].inner.check_cgroup(stream_handler))
+            .collect::<Vec<_>>();
+        changed.iter().for_each(|&idx| self.handle_change(idx));
+    }
+
+    fn handle_activate(&mut self, idx: usize) {
+        let app = &mut self.applications[idx];
+        info!("Application {} has a connection", app.inner.id);
+        app.activated = true;
     }
 
     fn handle_healthcheck_fail(&mut self, group: &str, _message: &str) {
@@ -171,12 +300,14 @@ impl Init {
         self.applications.iter().all(|app| app.inner.is_stopped())
     }
 
-    fn do_starts(&mut self, stream_handler: &mut stream::Handler) {
+    fn do_starts(&mut self, stream_handler: &stream::Handler) {
         let mut starts = self
             .applications
             .iter()
             .enumerate()
             .filter(|(_, app)| app.inner.is_idle())
+            .filter(|(_, app)| !app.inner.on_demand || app.activated)
+            .filter(|(_, app)| app.inner.schedule().is_none() || app.due)
             .filter(|(_, app)| app.start_time.map(|t| t <= Instant::now()).unwrap_or(true))
             .filter(|(_, app)| {
                 app.depends.iter().all(|idx| {
@@ -190,14 +321,54 @@ impl Init {
             let app = &mut self.applications[idx];
             app.start_time = None;
             if app.inner.start(stream_handler) {
-                app.kill_time = Some(Instant::now() + Duration::from_secs(30));
+                app.due = false;
+                app.set_kill_time();
             } else if app.inner.is_idle() {
                 app.start_time = Some(Instant::now() + Duration::from_secs(1));
             }
         });
     }
 
-    fn do_stops(&mut self) {
+    /// Marks scheduled applications as due when their time comes,
+    /// dealing with any run that hasn't finished yet
+    fn do_schedules(&mut self, stream_handler: &stream::Handler) {
+        let now = Instant::now();
+        self.applications
+            .iter_mut()
+            .filter(|app| app.next_run.map(|t| t <= now).unwrap_or(false))
+            .for_each(|app| {
+                let (overlap, next_run) = {
+                    let schedule = app.inner.schedule().unwrap();
+                    (schedule.overlap, schedule.next_run(app.next_run))
+                };
+                if app.inner.is_stopped() {
+                    app.due = true;
+                } else {
+                    match overlap {
+                        Overlap::Skip => {
+                            warn!("Application {} is still running, skipping run", app.inner.id)
+                        }
+                        Overlap::Queue => {
+                            info!("Application {} is still running, queueing run", app.inner.id);
+                            app.due = true;
+                        }
+                        Overlap::Kill => {
+                            warn!("Application {} is still running, stopping it", app.inner.id);
+                            if app.inner.stop(stream_handler) {
+                                app.set_kill_time();
+                            }
+                            app.due = true;
+                        }
+                    }
+                }
+                if next_run.is_none() {
+                    warn!("Application {} has no more runs scheduled", app.inner.id);
+                }
+                app.next_run = next_run;
+            });
+    }
+
+    fn do_stops(&mut self, stream_handler: &stream::Handler) {
         let stops = self
             .applications
             .iter()
@@ -212,10 +383,17 @@ impl Init {
 
         stops.iter().for_each(|idx| {
             let app = &mut self.applications[*idx];
-            if app.inner.stop() {
-                app.kill_time = Some(Instant::now() + Duration::from_secs(5));
-            }
             app.needs_stop = false;
+            // it may have stopped by itself since
+            if app.inner.is_stopped() {
+                return;
+            }
+            if app.inner.stop(stream_handler) {
+                app.set_kill_time();
+            } else if app.inner.is_idle() {
+                app.start_time = Some(Instant::now() + app.restart_delay());
+                app.deactivate();
+            }
         });
     }
 
@@ -229,11 +407,27 @@ impl Init {
             .collect::<Vec<_>>();
 
         kills.iter().for_each(|idx| {
-            self.applications[*idx].inner.kill();
-            self.applications[*idx].kill_time = None;
+            let app = &mut self.applications[*idx];
+            app.inner.kill();
+            // there's no child to reap when only processes in its
+            // cgroup remain so keep checking until they've gone
+            app.kill_time = if app.inner.is_draining() {
+                Some(Instant::now() + Duration::from_secs(1))
+            } else {
+                None
+            };
         });
     }
 
+    fn do_samples(&mut self) {
+        if self.sample_time <= Instant::now() {
+            self.applications
+                .iter_mut()
+                .for_each(|app| app.inner.sample_usage());
+            self.sample_time = Instant::now() + SAMPLE_INTERVAL;
+        }
+    }
+
     fn app_idxs<F>(&self, filter: F) -> Vec<usize>
     where
         F: Fn(&InitApp) -> bool,
@@ -247,16 +441,23 @@ impl Init {
     }
 
     fn get_next_timeout(&self) -> Option<Duration> {
-        let times = self.applications.iter().fold(Vec::new(), |mut times, app| {
+        let now = Instant::now();
+        let times = self.applications.iter().fold(vec![self.sample_time], |mut times, app| {
+            if app.inner.is_cgroup_only() {
+                times.push(now + CGROUP_INTERVAL);
+            }
             if let Some(t) = app.kill_time {
                 times.push(t);
             }
             if let Some(t) = app.start_time {
                 times.push(t);
             }
+            if let Some(t) = app.next_run {
+                times.push(t);
+            }
             times
         });
-        times.iter().min().map(|t| *t - (Instant::now().min(*t)))
+        times.iter().min().map(|t| *t - (now.min(*t)))
     }
 
     fn setup_dependencies(&mut self) {
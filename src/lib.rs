@@ -27,6 +27,7 @@ extern crate mio;
 extern crate nereon;
 extern crate nix;
 extern crate rand;
+extern crate regex;
 extern crate signal_hook;
 extern crate slab;
 extern crate syslog;
@@ -38,12 +39,24 @@ extern crate nereon_derive;
 extern crate log;
 
 mod application;
+mod cgroup;
 mod config;
 mod health;
 mod init;
 mod limit;
+mod namespace;
+mod privilege;
+mod pty;
+mod sandbox;
+mod sched;
+mod schedule;
+mod seccomp;
 mod signal;
+mod socket;
 mod stream;
+#[cfg(test)]
+mod testing;
+mod usage;
 
 pub fn riffol<T: std::iter::IntoIterator<Item = String>>(args: T) -> Result<(), String> {
     let config::Riffol {
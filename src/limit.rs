@@ -23,36 +23,256 @@
 
 extern crate libc;
 
-#[derive(Debug, Clone)]
+use std::cmp::min;
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Limit {
     Num(u64),
     Infinity,
 }
 
-#[derive(Debug, Clone)]
-pub enum RLimit {
-    Memory(Limit),
-    Procs(Limit),
-    Files(Limit),
+impl Limit {
+    /// The more restrictive of two limits
+    pub fn lower(self, other: Limit) -> Limit {
+        match (self, other) {
+            (Limit::Num(a), Limit::Num(b)) => Limit::Num(a.min(b)),
+            (Limit::Infinity, l) | (l, Limit::Infinity) => l,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resource {
+    As,
+    Core,
+    Cpu,
+    Data,
+    Fsize,
+    Locks,
+    Memlock,
+    Msgqueue,
+    Nice,
+    Nofile,
+    Nproc,
+    Rtprio,
+    Rttime,
+    Sigpending,
+    Stack,
+}
+
+/// How a resource's limit is measured, which determines the units
+/// accepted when parsing its value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unit {
+    Bytes,
+    Seconds,
+    Microseconds,
+    Count,
+}
+
+/// A resource limit. A missing `soft` or `hard` value leaves the
+/// current value unchanged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RLimit {
+    pub resource: Resource,
+    pub soft: Option<Limit>,
+    pub hard: Option<Limit>,
+}
+
+impl Resource {
+    pub fn from_name(name: &str) -> Option<Resource> {
+        Some(match name {
+            "as" => Resource::As,
+            "core" => Resource::Core,
+            "cpu" => Resource::Cpu,
+            "data" => Resource::Data,
+            "fsize" => Resource::Fsize,
+            "locks" => Resource::Locks,
+            "memlock" => Resource::Memlock,
+            "msgqueue" => Resource::Msgqueue,
+            "nice" => Resource::Nice,
+            "nofile" => Resource::Nofile,
+            "nproc" => Resource::Nproc,
+            "rtprio" => Resource::Rtprio,
+            "rttime" => Resource::Rttime,
+            "sigpending" => Resource::Sigpending,
+            "stack" => Resource::Stack,
+            _ => return None,
+        })
+    }
+
+    pub fn unit(self) -> Unit {
+        match self {
+            Resource::As
+            | Resource::Core
+            | Resource::Data
+            | Resource::Fsize
+            | Resource::Memlock
+            | Resource::Msgqueue
+            | Resource::Stack => Unit::Bytes,
+            Resource::Cpu => Unit::Seconds,
+            Resource::Rttime => Unit::Microseconds,
+            _ => Unit::Count,
+        }
+    }
+
+    fn as_raw(self) -> libc::c_int {
+        match self {
+            Resource::As => libc::RLIMIT_AS,
+            Resource::Core => libc::RLIMIT_CORE,
+            Resource::Cpu => libc::RLIMIT_CPU,
+            Resource::Data => libc::RLIMIT_DATA,
+            Resource::Fsize => libc::RLIMIT_FSIZE,
+            Resource::Locks => libc::RLIMIT_LOCKS,
+            Resource::Memlock => libc::RLIMIT_MEMLOCK,
+            Resource::Msgqueue => libc::RLIMIT_MSGQUEUE,
+            Resource::Nice => libc::RLIMIT_NICE,
+            Resource::Nofile => libc::RLIMIT_NOFILE,
+            Resource::Nproc => libc::RLIMIT_NPROC,
+            Resource::Rtprio => libc::RLIMIT_RTPRIO,
+            Resource::Rttime => libc::RLIMIT_RTTIME,
+            Resource::Sigpending => libc::RLIMIT_SIGPENDING,
+            Resource::Stack => libc::RLIMIT_STACK,
+        }
+    }
 }
 
-pub fn setlimit(rlimit: &RLimit) {
-    let (resource, limit) = match rlimit {
-        RLimit::Memory(v) => (libc::RLIMIT_AS, v),
-        RLimit::Procs(v) => (libc::RLIMIT_NPROC, v),
-        RLimit::Files(v) => (libc::RLIMIT_NOFILE, v),
+/// Parse a limit value such as `infinity`, `1024`, `512M` or `1h`.
+/// Sizes take a `K`, `M`, `G` or `T` suffix (powers of 1024) and
+/// times a `us`, `ms`, `s`, `m`, `h` or `d` suffix.
+pub fn parse_limit(s: &str, unit: Unit) -> Result<Limit, String> {
+    let s = s.trim();
+    if s == "infinity" || s == "unlimited" {
+        return Ok(Limit::Infinity);
+    }
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, suffix) = s.split_at(split);
+    let num = num
+        .parse::<u64>()
+        .map_err(|_| format!("Invalid limit value ({})", s))?;
+    let multiplier = match (unit, suffix) {
+        (_, "") => Some(1),
+        (Unit::Bytes, "K") => Some(1 << 10),
+        (Unit::Bytes, "M") => Some(1 << 20),
+        (Unit::Bytes, "G") => Some(1 << 30),
+        (Unit::Bytes, "T") => Some(1 << 40),
+        (Unit::Seconds, "s") | (Unit::Microseconds, "us") => Some(1),
+        (Unit::Seconds, "m") => Some(60),
+        (Unit::Seconds, "h") => Some(60 * 60),
+        (Unit::Seconds, "d") => Some(24 * 60 * 60),
+        (Unit::Microseconds, "ms") => Some(1000),
+        (Unit::Microseconds, "s") => Some(1_000_000),
+        (Unit::Microseconds, "m") => Some(60 * 1_000_000),
+        (Unit::Microseconds, "h") => Some(60 * 60 * 1_000_000),
+        _ => None,
     };
-    let limit = match limit {
-        Limit::Num(v) => *v,
+    multiplier
+        .and_then(|m| num.checked_mul(m))
+        .map(Limit::Num)
+        .ok_or_else(|| format!("Invalid limit value ({})", s))
+}
+
+/// Set a resource limit for the current process. Intended to be
+/// called between fork and exec so a failure aborts the spawn.
+pub fn setlimit(rlimit: &RLimit) -> io::Result<()> {
+    let raw = |limit: Limit| match limit {
+        Limit::Num(v) => v,
         Limit::Infinity => libc::RLIM_INFINITY,
     };
+    let resource = rlimit.resource.as_raw();
+    let mut current = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
     unsafe {
-        let _result = libc::setrlimit(
-            resource,
-            &libc::rlimit {
-                rlim_cur: limit,
-                rlim_max: limit,
-            },
-        );
+        if libc::getrlimit(resource, &mut current) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    let rlim_max = rlimit.hard.map_or(current.rlim_max, raw);
+    let limit = libc::rlimit {
+        // an inherited soft limit mustn't exceed a lower hard limit
+        rlim_cur: rlimit.soft.map_or(min(current.rlim_cur, rlim_max), raw),
+        rlim_max,
     };
+    match unsafe { libc::setrlimit(resource, &limit) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Sets the calling process's OOM killer score adjustment. `adj` is
+/// formatted beforehand as this is called between fork and exec.
+pub fn set_oom_score_adj(adj: &str) -> io::Result<()> {
+    let path = b"/proc/self/oom_score_adj\0";
+    unsafe {
+        let fd = libc::open(
+            path.as_ptr() as *const libc::c_char,
+            libc::O_WRONLY | libc::O_CLOEXEC,
+        );
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let result = libc::write(fd, adj.as_ptr() as *const libc::c_void, adj.len());
+        let error = io::Error::last_os_error();
+        libc::close(fd);
+        match result {
+            -1 => Err(error),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_limit, setlimit, Limit, RLimit, Resource, Unit};
+    use testing;
+
+    #[test]
+    fn test_parse_limit() {
+        assert_eq!(parse_limit("infinity", Unit::Bytes), Ok(Limit::Infinity));
+        assert_eq!(parse_limit("64", Unit::Count), Ok(Limit::Num(64)));
+        assert_eq!(parse_limit("512M", Unit::Bytes), Ok(Limit::Num(512 << 20)));
+        assert_eq!(parse_limit("1h", Unit::Seconds), Ok(Limit::Num(3600)));
+        assert_eq!(parse_limit("5ms", Unit::Microseconds), Ok(Limit::Num(5000)));
+        assert!(parse_limit("1h", Unit::Bytes).is_err());
+        assert!(parse_limit("1M", Unit::Count).is_err());
+        assert!(parse_limit("M", Unit::Bytes).is_err());
+        assert!(parse_limit("99999999999T", Unit::Bytes).is_err());
+    }
+
+    #[test]
+    fn test_setlimit() {
+        // lowering a soft limit is always permitted
+        let ok = RLimit {
+            resource: Resource::Core,
+            soft: Some(Limit::Num(0)),
+            hard: None,
+        };
+        assert!(setlimit(&ok).is_ok());
+        // a soft limit above the hard limit is not
+        let bad = RLimit {
+            resource: Resource::Core,
+            soft: Some(Limit::Num(2)),
+            hard: Some(Limit::Num(1)),
+        };
+        assert!(setlimit(&bad).is_err());
+
+        // a hard limit alone lowers the inherited soft limit with it, in
+        // a child so this process keeps its own limits
+        let output = testing::sh("ulimit -Sn; ulimit -Hn", || {
+            setlimit(&RLimit {
+                resource: Resource::Nofile,
+                soft: Some(Limit::Num(64)),
+                hard: None,
+            })?;
+            setlimit(&RLimit {
+                resource: Resource::Nofile,
+                soft: None,
+                hard: Some(Limit::Num(32)),
+            })
+        });
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "32\n32\n");
+    }
 }
@@ -0,0 +1,382 @@
+// Copyright (c) 2018, [Ribose Inc](https://www.ribose.com).
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// ``AS IS'' AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use libc;
+use std::io;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Signals passed on by a pid namespace's proxy process
+const FORWARDED: &[libc::c_int] = &[
+    libc::SIGHUP,
+    libc::SIGINT,
+    libc::SIGQUIT,
+    libc::SIGTERM,
+    libc::SIGUSR1,
+    libc::SIGUSR2,
+];
+
+/// The proxy's child, the pid namespace's init
+static INIT_PID: AtomicUsize = AtomicUsize::new(0);
+
+/// In the pid namespace's init, the writing end of a pipe the proxy
+/// waits on
+static PROXY_PIPE: AtomicUsize = AtomicUsize::new(0);
+
+/// A user namespace's id maps, "inside outside count" lines
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserMaps {
+    pub uid_map: String,
+    pub gid_map: String,
+}
+
+/// The namespaces an application is started in
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Namespaces {
+    pub mount: bool,
+    pub pid: bool,
+    pub net: bool,
+    pub ipc: bool,
+    pub uts: bool,
+    pub user: Option<UserMaps>,
+}
+
+impl Namespaces {
+    /// Moves the calling process into new namespaces.
+    ///
+    /// A new pid namespace only applies to the caller's children so
+    /// the caller forks. The child, pid 1 in the namespace, returns
+    /// and goes on to exec while the caller stays outside as a proxy:
+    /// it passes on signals then exits as the child does so to its
+    /// parent it looks like the application.
+    pub fn enter(&self) -> io::Result<()> {
+        let check = |result: libc::c_int| match result {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        };
+        if let Some(user) = self.user.as_ref() {
+            enter_user(user)?;
+        }
+        let flags = [
+            (self.mount, libc::CLONE_NEWNS),
+            (self.net, libc::CLONE_NEWNET),
+            (self.ipc, libc::CLONE_NEWIPC),
+            (self.uts, libc::CLONE_NEWUTS),
+            (self.pid, libc::CLONE_NEWPID),
+        ].iter()
+        .filter(|(wanted, _)| *wanted)
+        .fold(0, |flags, (_, flag)| flags | flag);
+        if flags != 0 {
+            check(unsafe { libc::unshare(flags) })?;
+        }
+        if self.mount {
+            // stop mounts propagating back to the parent namespace
+            check(unsafe {
+                libc::mount(
+                    ptr::null(),
+                    b"/\0".as_ptr() as *const libc::c_char,
+                    ptr::null(),
+                    libc::MS_REC | libc::MS_PRIVATE,
+                    ptr::null(),
+                )
+            })?;
+        }
+        if self.pid {
+            let mut fds = [0; 2];
+            check(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) })?;
+            match unsafe { libc::fork() } {
+                -1 => return Err(io::Error::last_os_error()),
+                0 => unsafe {
+                    libc::close(fds[0]);
+                    PROXY_PIPE.store(fds[1] as usize, Ordering::SeqCst);
+                },
+                child => unsafe {
+                    libc::close(fds[1]);
+                    proxy(child, fds[0])
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Has the pid namespace's init killed when its proxy dies, as
+    /// the proxy can't pass on a SIGKILL. Changing user clears this
+    /// so it's called afterwards.
+    pub fn die_with_proxy(&self) -> io::Result<()> {
+        if !self.pid {
+            return Ok(());
+        }
+        let pipe = PROXY_PIPE.load(Ordering::SeqCst) as libc::c_int;
+        unsafe {
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL, 0, 0, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            // getppid() is 0 for the proxy, which is outside the
+            // namespace, so the pipe tells whether it has died already:
+            // it waits on the reading end
+            let result = libc::write(pipe, b"x".as_ptr() as *const libc::c_void, 1);
+            let error = io::Error::last_os_error();
+            libc::close(pipe);
+            match result {
+                -1 => Err(error),
+                _ => Ok(()),
+            }
+        }
+    }
+}
+
+/// Moves the calling process into a new user namespace with `maps`.
+///
+/// A process in a new user namespace has no capabilities in its
+/// parent so can only map its own ids. Instead a helper forked
+/// beforehand, which keeps the caller's capabilities, writes the
+/// maps once the caller has unshared.
+fn enter_user(maps: &UserMaps) -> io::Result<()> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let helper = match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => unsafe {
+            libc::close(fds[1]);
+            let mut byte = 0u8;
+            let ready = libc::read(fds[0], &mut byte as *mut u8 as *mut libc::c_void, 1) == 1;
+            let pid = libc::getppid();
+            // without privileges gids can't be mapped while setgroups
+            // is allowed
+            let ok = ready
+                && (libc::geteuid() == 0 || write_proc(pid, b"setgroups\0", "deny").is_ok())
+                && write_proc(pid, b"uid_map\0", &maps.uid_map).is_ok()
+                && write_proc(pid, b"gid_map\0", &maps.gid_map).is_ok();
+            libc::_exit(if ok { 0 } else { 1 })
+        },
+        helper => helper,
+    };
+    let result = match unsafe { libc::unshare(libc::CLONE_NEWUSER) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    };
+    unsafe {
+        // closing without writing tells the helper to give up
+        if result.is_ok() {
+            libc::write(fds[1], b"x".as_ptr() as *const libc::c_void, 1);
+        }
+        libc::close(fds[0]);
+        libc::close(fds[1]);
+        let mut status = 0;
+        while libc::waitpid(helper, &mut status, 0) == -1 {
+            if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        result?;
+        if !libc::WIFEXITED(status) || libc::WEXITSTATUS(status) != 0 {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+    }
+    Ok(())
+}
+
+/// Writes `value` to `/proc/<pid>/<name>` where `name` is nul
+/// terminated
+fn write_proc(pid: libc::pid_t, name: &[u8], value: &str) -> io::Result<()> {
+    // format the path by hand as nothing may be allocated
+    let mut path = [0u8; 64];
+    let mut len = 0;
+    let mut push = |bytes: &[u8]| {
+        path[len..len + bytes.len()].copy_from_slice(bytes);
+        len += bytes.len();
+    };
+    push(b"/proc/");
+    let mut digits = [0u8; 10];
+    let mut n = pid as u32;
+    let mut i = digits.len();
+    loop {
+        i -= 1;
+        digits[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    push(&digits[i..]);
+    push(b"/");
+    push(name);
+    unsafe {
+        let fd = libc::open(
+            path.as_ptr() as *const libc::c_char,
+            libc::O_WRONLY | libc::O_CLOEXEC,
+        );
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let result = libc::write(fd, value.as_ptr() as *const libc::c_void, value.len());
+        let error = io::Error::last_os_error();
+        libc::close(fd);
+        match result {
+            -1 => Err(error),
+            _ => Ok(()),
+        }
+    }
+}
+
+extern "C" fn forward(sig: libc::c_int) {
+    unsafe { libc::kill(INIT_PID.load(Ordering::SeqCst) as libc::pid_t, sig) };
+}
+
+/// Runs the proxy for a pid namespace whose init is `child`. `ready`
+/// is closed or written to once `child` will die with the proxy.
+fn proxy(child: libc::pid_t, ready: libc::c_int) -> ! {
+    const SYS_CLOSE_RANGE: libc::c_long = 436;
+    unsafe {
+        let mut byte = 0u8;
+        while libc::read(ready, &mut byte as *mut u8 as *mut libc::c_void, 1) == -1
+            && io::Error::last_os_error().raw_os_error() == Some(libc::EINTR)
+        {}
+        // the proxy mustn't hold open any of its parent's files, in
+        // particular the pipe the parent waits on to learn whether
+        // exec succeeded
+        if libc::syscall(SYS_CLOSE_RANGE, 3, libc::c_uint::MAX, 0) == -1 {
+            let mut limit: libc::rlimit = ::std::mem::zeroed();
+            libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit);
+            (3..limit.rlim_cur.min(1 << 20) as libc::c_int).for_each(|fd| {
+                libc::close(fd);
+            });
+        }
+        INIT_PID.store(child as usize, Ordering::SeqCst);
+        for &sig in FORWARDED {
+            libc::signal(sig, forward as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        }
+        let mut status = 0;
+        while libc::waitpid(child, &mut status, 0) == -1 {
+            if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+                libc::_exit(127);
+            }
+        }
+        if libc::WIFSIGNALED(status) {
+            let sig = libc::WTERMSIG(status);
+            let mut set: libc::sigset_t = ::std::mem::zeroed();
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, sig);
+            libc::signal(sig, libc::SIG_DFL);
+            libc::sigprocmask(libc::SIG_UNBLOCK, &set, ptr::null_mut());
+            libc::kill(libc::getpid(), sig);
+            libc::_exit(128 + sig);
+        }
+        libc::_exit(libc::WEXITSTATUS(status))
+    }
+}
+
+/// Parses id map lines, "inside outside count", into the form
+/// written to `/proc/<pid>/uid_map`
+pub fn parse_id_map(lines: &[String]) -> Result<String, String> {
+    lines
+        .iter()
+        .map(|line| {
+            let ids = line
+                .split_whitespace()
+                .map(|id| id.parse::<u32>())
+                .collect::<Result<Vec<_>, _>>();
+            match ids {
+                Ok(ref ids) if ids.len() == 3 && ids[2] > 0 => {
+                    Ok(format!("{} {} {}", ids[0], ids[1], ids[2]))
+                }
+                _ => Err(format!("Invalid id map ({})", line)),
+            }
+        }).collect::<Result<Vec<_>, _>>()
+        .map(|lines| lines.join("\n"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_id_map, Namespaces, UserMaps};
+    use std::fs;
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+    use std::thread;
+    use std::time::Duration;
+    use testing;
+
+    #[test]
+    fn test_parse_id_map() {
+        let map = |lines: &[&str]| {
+            parse_id_map(&lines.iter().map(|l| l.to_string()).collect::<Vec<_>>())
+        };
+        assert_eq!(map(&["0 1000 1", " 1  100000 65536 "]), Ok("0 1000 1\n1 100000 65536".to_owned()));
+        assert!(map(&["0 1000"]).is_err());
+        assert!(map(&["0 1000 0"]).is_err());
+        assert!(map(&["a b c"]).is_err());
+    }
+
+    #[test]
+    #[ignore = "needs root"]
+    fn test_pid_namespace() {
+        let namespaces = Namespaces {
+            pid: true,
+            ..Default::default()
+        };
+        let first = namespaces.clone();
+        let output = testing::sh("echo $$; exit 3", move || first.enter());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n");
+        assert_eq!(output.status.code(), Some(3));
+
+        // the init dies with a killed proxy
+        let mut command = Command::new("/bin/sleep");
+        command.arg("10");
+        unsafe {
+            command.pre_exec(move || {
+                namespaces.enter()?;
+                namespaces.die_with_proxy()
+            })
+        };
+        let mut proxy = command.spawn().unwrap();
+        let children = format!("/proc/{0}/task/{0}/children", proxy.id());
+        let init = loop {
+            match fs::read_to_string(&children).unwrap().trim().parse::<u32>() {
+                Ok(pid) => break pid,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        proxy.kill().unwrap();
+        proxy.wait().unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let stat = fs::read_to_string(format!("/proc/{}/stat", init)).unwrap_or_default();
+        assert!(stat.is_empty() || stat.contains(") Z "));
+    }
+
+    #[test]
+    #[ignore = "needs root"]
+    fn test_user_namespace() {
+        let namespaces = Namespaces {
+            user: Some(UserMaps {
+                uid_map: "0 0 1\n1 100000 1000".to_owned(),
+                gid_map: "0 0 1".to_owned(),
+            }),
+            ..Default::default()
+        };
+        let output = testing::sh("id -u; wc -l < /proc/self/uid_map", move || namespaces.enter());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "0\n2\n");
+        assert!(output.status.success());
+    }
+}
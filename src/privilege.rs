@@ -0,0 +1,262 @@
+// Copyright (c) 2018, [Ribose Inc](https://www.ribose.com).
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// ``AS IS'' AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use libc;
+use std::io;
+
+const CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+/// Capability names, without the `CAP_` prefix, by number
+const CAPABILITIES: &[&str] = &[
+    "chown",
+    "dac_override",
+    "dac_read_search",
+    "fowner",
+    "fsetid",
+    "kill",
+    "setgid",
+    "setuid",
+    "setpcap",
+    "linux_immutable",
+    "net_bind_service",
+    "net_broadcast",
+    "net_admin",
+    "net_raw",
+    "ipc_lock",
+    "ipc_owner",
+    "sys_module",
+    "sys_rawio",
+    "sys_chroot",
+    "sys_ptrace",
+    "sys_pacct",
+    "sys_admin",
+    "sys_boot",
+    "sys_nice",
+    "sys_resource",
+    "sys_time",
+    "sys_tty_config",
+    "mknod",
+    "lease",
+    "audit_write",
+    "audit_control",
+    "setfcap",
+    "mac_override",
+    "mac_admin",
+    "syslog",
+    "wake_alarm",
+    "block_suspend",
+    "audit_read",
+    "perfmon",
+    "bpf",
+    "checkpoint_restore",
+];
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// The user an application runs as and the privileges it keeps.
+/// Capability sets are bit masks indexed by capability number.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Privileges {
+    pub uid: Option<libc::uid_t>,
+    pub gid: Option<libc::gid_t>,
+    /// Capabilities kept across the switch to `uid` and exec
+    pub capabilities: Option<u64>,
+    pub bounding_set: Option<u64>,
+    pub no_new_privileges: bool,
+}
+
+impl Privileges {
+    /// Switches the calling process to the application's user then
+    /// limits its capabilities
+    pub fn apply(&self) -> io::Result<()> {
+        let check = |result: libc::c_int| match result {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        };
+        // dropping from the bounding set needs CAP_SETPCAP, which is
+        // lost with the switch to uid
+        if let Some(set) = self.bounding_set {
+            for cap in 0..64 {
+                if set & 1 << cap != 0 {
+                    continue;
+                }
+                if unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) } == -1 {
+                    // past the last capability the kernel knows
+                    match io::Error::last_os_error().raw_os_error() {
+                        Some(libc::EINVAL) => break,
+                        _ => return Err(io::Error::last_os_error()),
+                    }
+                }
+            }
+        }
+        if self.capabilities.is_some() && self.uid.is_some() {
+            // otherwise changing uid from root clears the permitted set
+            check(unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) })?;
+        }
+        if let Some(gid) = self.gid {
+            // supplementary groups can't always be set, eg. in a user
+            // namespace, which only matters if there are some
+            if unsafe { libc::setgroups(1, &gid) } == -1
+                && unsafe { libc::getgroups(0, ::std::ptr::null_mut()) } != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            check(unsafe { libc::setgid(gid) })?;
+        }
+        if let Some(uid) = self.uid {
+            check(unsafe { libc::setuid(uid) })?;
+        }
+        if let Some(caps) = self.capabilities {
+            let set = |caps: u32| CapData {
+                effective: caps,
+                permitted: caps,
+                inheritable: caps,
+            };
+            capset(&[set(caps as u32), set((caps >> 32) as u32)])?;
+            // ambient capabilities survive exec of an unprivileged
+            // program
+            for cap in (0..64).filter(|cap| caps & 1 << cap != 0) {
+                check(unsafe {
+                    libc::prctl(
+                        libc::PR_CAP_AMBIENT,
+                        libc::PR_CAP_AMBIENT_RAISE,
+                        cap as libc::c_ulong,
+                        0,
+                        0,
+                    )
+                })?;
+            }
+        }
+        if self.no_new_privileges && unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+fn capset(data: &[CapData; 2]) -> io::Result<()> {
+    let mut header = CapHeader {
+        version: CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    match unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Parses capability names, eg. `CAP_NET_BIND_SERVICE` or
+/// `net_bind_service`, into a set
+pub fn parse_capabilities(names: &[String]) -> Result<u64, String> {
+    names.iter().try_fold(0, |set, name| {
+        let lower = name.to_lowercase();
+        let short = lower.trim_start_matches("cap_");
+        CAPABILITIES
+            .iter()
+            .position(|c| *c == short)
+            .map(|cap| set | 1 << cap)
+            .ok_or_else(|| format!("No such capability \"{}\"", name))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_capabilities, Privileges};
+    use testing;
+
+    #[test]
+    fn test_parse_capabilities() {
+        let parse = |names: &[&str]| {
+            parse_capabilities(&names.iter().map(|n| n.to_string()).collect::<Vec<_>>())
+        };
+        assert_eq!(parse(&[]), Ok(0));
+        assert_eq!(parse(&["CAP_CHOWN", "net_bind_service"]), Ok(1 | 1 << 10));
+        assert_eq!(parse(&["cap_checkpoint_restore"]), Ok(1 << 40));
+        assert!(parse(&["CAP_"]).is_err());
+        assert!(parse(&["fly"]).is_err());
+    }
+
+    #[test]
+    #[ignore = "needs root"]
+    fn test_apply() {
+        let status = |privileges: Privileges| {
+            let output = testing::sh(
+                "exec grep -E '^(Uid|Gid|CapEff|CapAmb|CapBnd|NoNewPrivs)' /proc/self/status",
+                move || privileges.apply(),
+            );
+            let mut status = String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(|l| l.split_whitespace().collect::<Vec<_>>().join(" "))
+                .collect::<Vec<_>>();
+            // the order of fields varies between kernels
+            status.sort();
+            status
+        };
+        assert_eq!(
+            status(Privileges {
+                uid: Some(65534),
+                gid: Some(65534),
+                capabilities: Some(1 << 10),
+                bounding_set: Some(1 << 10 | 1 << 13),
+                no_new_privileges: true,
+            }),
+            vec![
+                "CapAmb: 0000000000000400",
+                "CapBnd: 0000000000002400",
+                "CapEff: 0000000000000400",
+                "Gid: 65534 65534 65534 65534",
+                "NoNewPrivs: 1",
+                "Uid: 65534 65534 65534 65534",
+            ]
+        );
+        assert_eq!(
+            status(Privileges {
+                uid: Some(65534),
+                gid: Some(65534),
+                capabilities: None,
+                bounding_set: Some(1 << 10),
+                no_new_privileges: false,
+            }),
+            vec![
+                "CapAmb: 0000000000000000",
+                "CapBnd: 0000000000000400",
+                "CapEff: 0000000000000000",
+                "Gid: 65534 65534 65534 65534",
+                "NoNewPrivs: 0",
+                "Uid: 65534 65534 65534 65534",
+            ]
+        );
+    }
+}
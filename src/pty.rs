@@ -0,0 +1,88 @@
+// Copyright (c) 2018, [Ribose Inc](https://www.ribose.com).
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// ``AS IS'' AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use libc;
+use nix::fcntl::{open, OFlag};
+use nix::pty::{grantpt, posix_openpt, ptsname_r, unlockpt};
+use nix::sys::stat::Mode;
+use nix::unistd::setsid;
+use nix::Error;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+fn to_io(e: Error) -> io::Error {
+    match e {
+        Error::Sys(errno) => io::Error::from_raw_os_error(errno as i32),
+        _ => io::Error::from_raw_os_error(libc::EINVAL),
+    }
+}
+
+/// Opens a new pseudo-terminal returning its master and slave. Both
+/// are close-on-exec so only the slave's duplicates (ie. the child's
+/// standard streams) survive into the child.
+pub fn open_pty() -> io::Result<(File, File)> {
+    let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC).map_err(to_io)?;
+    grantpt(&master).map_err(to_io)?;
+    unlockpt(&master).map_err(to_io)?;
+    let name = ptsname_r(&master).map_err(to_io)?;
+    let slave = open(
+        name.as_str(),
+        OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    ).map_err(to_io)?;
+    Ok(unsafe {
+        (
+            File::from_raw_fd(master.into_raw_fd()),
+            File::from_raw_fd(slave),
+        )
+    })
+}
+
+/// Starts a new session with the terminal on stdin as its
+/// controlling terminal. Must only be called in a child between fork
+/// and exec.
+pub fn set_controlling_terminal() -> io::Result<()> {
+    setsid().map_err(to_io)?;
+    if unsafe { libc::ioctl(0, libc::TIOCSCTTY, 0) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn test_open_pty() {
+        let (mut master, mut slave) = super::open_pty().unwrap();
+        assert_eq!(unsafe { ::libc::isatty(slave.as_raw_fd()) }, 1);
+        slave.write_all(b"hello\n").unwrap();
+        let mut data = [0u8; 16];
+        let n = master.read(&mut data).unwrap();
+        // the terminal translates "\n" to "\r\n"
+        assert_eq!(&data[..n], b"hello\r\n");
+    }
+}
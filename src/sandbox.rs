@@ -0,0 +1,200 @@
+// Copyright (c) 2018, [Ribose Inc](https://www.ribose.com).
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// ``AS IS'' AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use libc;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem;
+use std::ptr;
+
+/// Mount flags kept when a bind mount is made read-only. An
+/// unprivileged remount fails unless it keeps them.
+const KEPT_FLAGS: libc::c_ulong = libc::MS_NOSUID
+    | libc::MS_NODEV
+    | libc::MS_NOEXEC
+    | libc::MS_NOATIME
+    | libc::MS_NODIRATIME
+    | libc::MS_RELATIME;
+
+/// A directory to `chroot` into and the working directory within it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Root {
+    pub path: CString,
+    pub dir: CString,
+}
+
+/// The parts of the filesystem an application may see and write.
+/// Paths are as seen from outside any new root.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sandbox {
+    pub root: Option<Root>,
+    pub read_write_paths: Vec<CString>,
+    pub read_only_paths: Vec<CString>,
+    pub inaccessible_paths: Vec<CString>,
+    /// Directories given a new, empty tmpfs
+    pub private_tmp: Vec<CString>,
+}
+
+impl Sandbox {
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+            && self.read_write_paths.is_empty()
+            && self.read_only_paths.is_empty()
+            && self.inaccessible_paths.is_empty()
+            && self.private_tmp.is_empty()
+    }
+
+    /// Confines the calling process to the sandbox in a new mount
+    /// namespace
+    pub fn apply(&self) -> io::Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        check(unsafe { libc::unshare(libc::CLONE_NEWNS) })?;
+        mount(None, cstr(b"/\0"), None, libc::MS_REC | libc::MS_PRIVATE, None)?;
+        for dir in &self.private_tmp {
+            mount(
+                Some(cstr(b"tmpfs\0")),
+                dir,
+                Some(cstr(b"tmpfs\0")),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                Some(cstr(b"mode=1777\0")),
+            )?;
+        }
+        // writable paths and tmpfs are mounted first so a read-only
+        // bind mount of a directory above them copies them as they are
+        for path in &self.read_write_paths {
+            mount(Some(path), path, None, libc::MS_BIND | libc::MS_REC, None)?;
+        }
+        for path in &self.read_only_paths {
+            bind_read_only(path, path)?;
+        }
+        for path in &self.inaccessible_paths {
+            // directories are hidden under an empty tmpfs, anything
+            // else under /dev/null
+            let mut stat: libc::stat = unsafe { mem::zeroed() };
+            check(unsafe { libc::stat(path.as_ptr(), &mut stat) })?;
+            if stat.st_mode & libc::S_IFMT == libc::S_IFDIR {
+                mount(
+                    Some(cstr(b"tmpfs\0")),
+                    path,
+                    Some(cstr(b"tmpfs\0")),
+                    libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+                    Some(cstr(b"mode=000\0")),
+                )?;
+            } else {
+                bind_read_only(cstr(b"/dev/null\0"), path)?;
+            }
+        }
+        if let Some(root) = self.root.as_ref() {
+            check(unsafe { libc::chroot(root.path.as_ptr()) })?;
+            check(unsafe { libc::chdir(root.dir.as_ptr()) })?;
+        }
+        Ok(())
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+fn mount(
+    source: Option<&CStr>,
+    target: &CStr,
+    fstype: Option<&CStr>,
+    flags: libc::c_ulong,
+    data: Option<&CStr>,
+) -> io::Result<()> {
+    let ptr = |s: Option<&CStr>| s.map_or(ptr::null(), |s| s.as_ptr());
+    check(unsafe {
+        libc::mount(
+            ptr(source),
+            target.as_ptr(),
+            ptr(fstype),
+            flags,
+            ptr(data).cast(),
+        )
+    })
+}
+
+fn bind_read_only(source: &CStr, target: &CStr) -> io::Result<()> {
+    mount(Some(source), target, None, libc::MS_BIND | libc::MS_REC, None)?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    check(unsafe { libc::statvfs(target.as_ptr(), &mut stat) })?;
+    mount(
+        None,
+        target,
+        None,
+        libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY | stat.f_flag & KEPT_FLAGS,
+        None,
+    )
+}
+
+fn cstr(bytes: &'static [u8]) -> &'static CStr {
+    CStr::from_bytes_with_nul(bytes).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Root, Sandbox};
+    use std::ffi::CString;
+    use std::fs;
+    use std::path::Path;
+    use testing;
+
+    #[test]
+    #[ignore = "needs root"]
+    fn test_apply() {
+        let base = ::std::env::temp_dir().join(format!("riffol-sandbox-{}", ::std::process::id()));
+        let path = |p: &str| base.join(p);
+        let cpath = |p: &str| CString::new(path(p).to_str().unwrap()).unwrap();
+        fs::create_dir_all(path("ro/rw")).unwrap();
+        fs::create_dir_all(path("hidden")).unwrap();
+        fs::create_dir_all(path("tmp")).unwrap();
+        fs::write(path("hidden/secret"), "secret").unwrap();
+        fs::write(path("secret"), "secret").unwrap();
+
+        let sandbox = Sandbox {
+            root: Some(Root {
+                path: CString::new("/").unwrap(),
+                dir: cpath("ro"),
+            }),
+            read_write_paths: vec![cpath("ro/rw")],
+            read_only_paths: vec![cpath("ro")],
+            inaccessible_paths: vec![cpath("hidden"), cpath("secret")],
+            private_tmp: vec![cpath("tmp")],
+        };
+        let output = testing::sh(
+            "pwd; touch x || echo ro; touch rw/x && echo rw; ls ../hidden; cat ../secret; touch ../tmp/x && echo tmp",
+            move || sandbox.apply(),
+        );
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        let tmp_written = Path::new(&path("tmp/x")).exists();
+        fs::remove_dir_all(&base).unwrap();
+        assert_eq!(stdout, format!("{}\nro\nrw\ntmp\n", path("ro").display()));
+        assert!(!tmp_written);
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright (c) 2018, [Ribose Inc](https://www.ribose.com).
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// ``AS IS'' AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use libc;
+use std::io;
+use std::mem;
+
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+/// A CPU scheduling policy. `Fifo` and `Rr` take a real-time priority
+/// (1 to 99).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Policy {
+    Other,
+    Batch,
+    Idle,
+    Fifo(u32),
+    Rr(u32),
+}
+
+/// An I/O scheduling class. `Realtime` and `BestEffort` take a level
+/// (0 to 7, lower being higher priority).
+#[derive(Debug, Clone, PartialEq)]
+pub enum IoPriority {
+    Realtime(u32),
+    BestEffort(u32),
+    Idle,
+}
+
+/// How an application's processes are scheduled
+#[derive(Debug, Clone, Default)]
+pub struct Scheduling {
+    pub nice: Option<i32>,
+    pub cpu_affinity: Option<Vec<usize>>,
+    pub policy: Option<Policy>,
+    pub ioprio: Option<IoPriority>,
+}
+
+impl Scheduling {
+    /// Applies the settings to the current process
+    pub fn apply(&self) -> io::Result<()> {
+        let check = |result: libc::c_int| match result {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        };
+        if let Some(cpus) = self.cpu_affinity.as_ref() {
+            unsafe {
+                let mut set: libc::cpu_set_t = mem::zeroed();
+                libc::CPU_ZERO(&mut set);
+                cpus.iter().for_each(|&cpu| libc::CPU_SET(cpu, &mut set));
+                check(libc::sched_setaffinity(
+                    0,
+                    mem::size_of::<libc::cpu_set_t>(),
+                    &set,
+                ))?;
+            }
+        }
+        if let Some(policy) = self.policy.as_ref() {
+            let (policy, priority) = match policy {
+                Policy::Other => (libc::SCHED_OTHER, 0),
+                Policy::Batch => (libc::SCHED_BATCH, 0),
+                Policy::Idle => (libc::SCHED_IDLE, 0),
+                Policy::Fifo(p) => (libc::SCHED_FIFO, *p),
+                Policy::Rr(p) => (libc::SCHED_RR, *p),
+            };
+            let param = libc::sched_param {
+                sched_priority: priority as libc::c_int,
+            };
+            check(unsafe { libc::sched_setscheduler(0, policy, &param) })?;
+        }
+        if let Some(nice) = self.nice {
+            check(unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) })?;
+        }
+        if let Some(ioprio) = self.ioprio.as_ref() {
+            let (class, level) = match ioprio {
+                IoPriority::Realtime(l) => (1, *l),
+                IoPriority::BestEffort(l) => (2, *l),
+                IoPriority::Idle => (3, 0),
+            };
+            let ioprio = class << IOPRIO_CLASS_SHIFT | level as libc::c_int;
+            check(unsafe {
+                libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) as libc::c_int
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a CPU list such as "0-3,8"
+pub fn parse_cpu_list(s: &str) -> Result<Vec<usize>, String> {
+    let bad = || format!("Invalid CPU list ({})", s);
+    let mut cpus = Vec::new();
+    for range in s.split(',').map(|r| r.trim()) {
+        let bounds = range
+            .splitn(2, '-')
+            .map(|n| n.trim().parse::<usize>().map_err(|_| bad()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let (first, last) = (bounds[0], *bounds.last().unwrap());
+        if first > last || last >= mem::size_of::<libc::cpu_set_t>() * 8 {
+            return Err(bad());
+        }
+        cpus.extend(first..=last);
+    }
+    cpus.sort();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_cpu_list, Policy, Scheduling};
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0"), Ok(vec![0]));
+        assert_eq!(parse_cpu_list("0-3, 8,2"), Ok(vec![0, 1, 2, 3, 8]));
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("0-").is_err());
+        assert!(parse_cpu_list("99999").is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        // only changes which any thread may make to itself as these
+        // apply to the calling thread
+        let scheduling = Scheduling {
+            nice: Some(19),
+            policy: Some(Policy::Batch),
+            ..Default::default()
+        };
+        ::std::thread::spawn(move || scheduling.apply())
+            .join()
+            .unwrap()
+            .unwrap();
+    }
+}
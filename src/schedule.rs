@@ -0,0 +1,307 @@
+// Copyright (c) 2018, [Ribose Inc](https://www.ribose.com).
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// ``AS IS'' AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use libc;
+use std::mem;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const MONTHS: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+const WEEKDAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// How many years ahead to look for a time matching a cron expression
+/// before deciding it never matches
+const MAX_YEARS: i32 = 10;
+
+/// When a scheduled application runs
+#[derive(Debug, PartialEq)]
+pub struct Schedule {
+    pub timing: Timing,
+    pub overlap: Overlap,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Timing {
+    Cron(Cron),
+    Interval(Duration),
+}
+
+/// What to do when a run is due but the previous one hasn't finished
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Overlap {
+    Skip,
+    Queue,
+    Kill,
+}
+
+impl Schedule {
+    /// When the application should next run, given when it was last
+    /// due. Returns `None` if it will never run again.
+    pub fn next_run(&self, last: Option<Instant>) -> Option<Instant> {
+        let now = Instant::now();
+        match self.timing {
+            // runs keep to the interval unless they've fallen behind
+            Timing::Interval(interval) => Some(
+                last.map(|t| t + interval)
+                    .filter(|&t| t > now)
+                    .unwrap_or(now + interval),
+            ),
+            Timing::Cron(ref cron) => {
+                let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+                // a run that's just become due mustn't be scheduled
+                // again because the clock is slightly behind
+                let secs = since_epoch.as_secs() + if last.is_some() { 1 } else { 0 };
+                let next = cron.next_after(secs as libc::time_t)?;
+                let delay = Duration::from_secs(next as u64).checked_sub(since_epoch);
+                Some(now + delay.unwrap_or_default())
+            }
+        }
+    }
+}
+
+/// A cron expression's minutes, hours, days of the month, months and
+/// days of the week, as bit sets
+#[derive(Debug, PartialEq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the days of the month and week were given as `*`. If
+    /// neither was, a day matching either of them will do.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    /// Parses a standard five field cron expression, or one of the
+    /// `@hourly`, `@daily`, `@weekly`, `@monthly` or `@yearly`
+    /// shorthands
+    pub fn parse(expr: &str) -> Result<Self, String> {
+        let fields = match expr {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            expr => expr,
+        }.split_whitespace()
+            .collect::<Vec<_>>();
+        let invalid = || format!("Invalid cron expression ({})", expr);
+        if fields.len() != 5 {
+            return Err(invalid());
+        }
+        let minutes = parse_field(fields[0], 0, 59, &[]).ok_or_else(invalid)?;
+        let hours = parse_field(fields[1], 0, 23, &[]).ok_or_else(invalid)?;
+        let days = parse_field(fields[2], 1, 31, &[]).ok_or_else(invalid)?;
+        let months = parse_field(fields[3], 1, 12, MONTHS).ok_or_else(invalid)?;
+        // Sunday is either 0 or 7
+        let weekdays = parse_field(fields[4], 0, 7, WEEKDAYS).ok_or_else(invalid)?;
+        Ok(Cron {
+            minutes,
+            hours,
+            days,
+            months,
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            any_day: fields[2].starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        })
+    }
+
+    /// Finds the first minute after `time` that matches, in local
+    /// time. Returns `None` if there isn't one in the next few years.
+    pub fn next_after(&self, time: libc::time_t) -> Option<libc::time_t> {
+        let start = (time / 60 + 1) * 60;
+        let mut tm: libc::tm = unsafe { mem::zeroed() };
+        let mut last: libc::tm = unsafe { mem::zeroed() };
+        if unsafe { libc::localtime_r(&time, &mut last).is_null() }
+            || unsafe { libc::localtime_r(&start, &mut tm).is_null() }
+        {
+            return None;
+        }
+        let end_year = tm.tm_year + MAX_YEARS;
+        let mut before = wall_clock(&last);
+        // mktime() normalises the fields after each step. Moving to
+        // the next month or day starts from midnight, which is left to
+        // work out whether daylight saving applies, while moving to
+        // the next hour or minute keeps to the current offset so time
+        // only goes forward when the clocks change.
+        while tm.tm_year <= end_year {
+            // the hour repeated when the clocks go back is skipped so
+            // nothing runs twice
+            if wall_clock(&tm) < before {
+                tm.tm_hour = before.3 + 1;
+                tm.tm_min = 0;
+                if unsafe { libc::mktime(&mut tm) } == -1 {
+                    return None;
+                }
+            }
+            before = wall_clock(&tm);
+            if !has(self.months, tm.tm_mon + 1) {
+                tm.tm_mon += 1;
+                tm.tm_mday = 1;
+                tm.tm_hour = 0;
+                tm.tm_min = 0;
+                tm.tm_isdst = -1;
+            } else if !self.matches_day(&tm) {
+                tm.tm_mday += 1;
+                tm.tm_hour = 0;
+                tm.tm_min = 0;
+                tm.tm_isdst = -1;
+            } else if !has(self.hours, tm.tm_hour) {
+                tm.tm_hour += 1;
+                tm.tm_min = 0;
+            } else if !has(self.minutes, tm.tm_min) {
+                tm.tm_min += 1;
+            } else {
+                return Some(unsafe { libc::mktime(&mut tm) });
+            }
+            if unsafe { libc::mktime(&mut tm) } == -1 {
+                return None;
+            }
+        }
+        None
+    }
+
+    fn matches_day(&self, tm: &libc::tm) -> bool {
+        let day = has(self.days, tm.tm_mday);
+        let weekday = has(self.weekdays, tm.tm_wday);
+        if self.any_day || self.any_weekday {
+            day && weekday
+        } else {
+            day || weekday
+        }
+    }
+}
+
+fn wall_clock(tm: &libc::tm) -> (libc::c_int, libc::c_int, libc::c_int, libc::c_int, libc::c_int) {
+    (tm.tm_year, tm.tm_mon, tm.tm_mday, tm.tm_hour, tm.tm_min)
+}
+
+fn has(bits: u64, value: libc::c_int) -> bool {
+    bits & 1 << value != 0
+}
+
+/// Parses a comma separated list of values, ranges (`a-b`) or `*`,
+/// each optionally followed by a step (`/n`), into a bit set. Values
+/// may also be given by `names`, starting from `min`.
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Option<u64> {
+    let value = |s: &str| {
+        names
+            .iter()
+            .position(|n| n.eq_ignore_ascii_case(s))
+            .map(|i| i as u32 + min)
+            .or_else(|| s.parse().ok())
+            .filter(|v| (min..=max).contains(v))
+    };
+    field.split(',').try_fold(0, |bits, item| {
+        let mut parts = item.splitn(2, '/');
+        let range = parts.next()?;
+        let step = match parts.next() {
+            Some(step) => Some(step.parse::<usize>().ok().filter(|&s| s > 0)?),
+            None => None,
+        };
+        let (first, last) = if range == "*" {
+            (min, max)
+        } else {
+            let mut ends = range.splitn(2, '-');
+            let first = value(ends.next()?)?;
+            match ends.next() {
+                Some(last) => (first, value(last)?),
+                // a single value with a step runs to the end
+                None if step.is_some() => (first, max),
+                None => (first, first),
+            }
+        };
+        if first > last {
+            return None;
+        }
+        Some(
+            (first..=last)
+                .step_by(step.unwrap_or(1))
+                .fold(bits, |bits, v| bits | 1 << v),
+        )
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::Cron;
+    use libc;
+    use std::mem;
+
+    #[test]
+    fn test_parse() {
+        let cron = Cron::parse("*/15 9-17 * jan-mar,DEC 1-5").unwrap();
+        assert_eq!(cron.minutes, 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(cron.hours, 0x3fe00);
+        assert_eq!(cron.days, 0xffff_fffe);
+        assert_eq!(cron.months, 0b1_0000_0000_1110);
+        assert_eq!(cron.weekdays, 0b11_1110);
+        assert!(cron.any_day && !cron.any_weekday);
+
+        let cron = Cron::parse("5/20 0 1,15 * sun,7").unwrap();
+        assert_eq!(cron.minutes, 1 << 5 | 1 << 25 | 1 << 45);
+        assert_eq!(cron.days, 1 << 1 | 1 << 15);
+        assert_eq!(cron.weekdays, 1);
+        assert!(!cron.any_day && !cron.any_weekday);
+
+        assert_eq!(Cron::parse("@daily"), Cron::parse("0 0 * * *"));
+        for expr in &["* * * *", "60 * * * *", "* * 0 * *", "5-1 * * * *", "*/0 * * * *", "@often"] {
+            assert!(Cron::parse(expr).is_err(), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_next_after() {
+        let now = unsafe { libc::time(::std::ptr::null_mut()) };
+        let local = |t| {
+            let mut tm: libc::tm = unsafe { mem::zeroed() };
+            unsafe { libc::localtime_r(&t, &mut tm) };
+            tm
+        };
+
+        let next = Cron::parse("* * * * *").unwrap().next_after(now).unwrap();
+        assert_eq!(next, (now / 60 + 1) * 60);
+
+        let next = Cron::parse("30 4 * * *").unwrap().next_after(now).unwrap();
+        let tm = local(next);
+        assert_eq!((tm.tm_hour, tm.tm_min, tm.tm_sec), (4, 30, 0));
+        assert!(next > now && next <= now + 25 * 3600);
+
+        let next = Cron::parse("0 12 29 feb *").unwrap().next_after(now).unwrap();
+        let tm = local(next);
+        assert_eq!((tm.tm_mon, tm.tm_mday, tm.tm_hour), (1, 29, 12));
+
+        // either the 13th or a Friday
+        let next = Cron::parse("0 0 13 * fri").unwrap().next_after(now).unwrap();
+        let tm = local(next);
+        assert!(tm.tm_mday == 13 || tm.tm_wday == 5);
+        assert!(next <= now + 8 * 24 * 3600);
+
+        assert_eq!(Cron::parse("0 0 30 2 *").unwrap().next_after(now), None);
+    }
+}
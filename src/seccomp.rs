@@ -0,0 +1,637 @@
+// Copyright (c) 2018, [Ribose Inc](https://www.ribose.com).
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// ``AS IS'' AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use libc;
+use std::io;
+
+/// Only x86_64 system calls are known
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+
+#[cfg(not(target_arch = "x86_64"))]
+const AUDIT_ARCH: Option<u32> = None;
+
+const UNSUPPORTED: &str = "syscall_filter isn't supported on this architecture";
+/// Set in the numbers of x32 system calls, which are always denied
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_JMP_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+
+/// Offsets into `struct seccomp_data`
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_RET_DATA: u32 = 0xffff;
+
+/// Groups of system calls, named as in systemd. Members may be other
+/// groups.
+const GROUPS: &[(&str, &str)] = &[
+    // Needed by any program to start and exit
+    ("@default", "access arch_prctl brk clock_getres clock_gettime clock_nanosleep close execve execveat exit exit_group faccessat faccessat2 fstat futex get_robust_list getegid geteuid getgid getpid getppid getrandom getresgid getresuid gettid gettimeofday getuid membarrier mmap mprotect munmap nanosleep newfstatat open openat pread64 prlimit64 read restart_syscall rseq rt_sigreturn sched_getaffinity sched_yield set_robust_list set_tid_address sigaltstack statx time uname"),
+    // Reading and writing open files
+    ("@basic-io", "close close_range dup dup2 dup3 lseek pread64 preadv preadv2 pwrite64 pwritev pwritev2 read readv write writev"),
+    // Files and directories
+    ("@file-system", "access chdir chmod close creat faccessat faccessat2 fallocate fchdir fchmod fchmodat fcntl fgetxattr flistxattr fremovexattr fsetxattr fstat fstatfs ftruncate futimesat getcwd getdents getdents64 getxattr inotify_add_watch inotify_init inotify_init1 inotify_rm_watch lgetxattr link linkat listxattr llistxattr lremovexattr lsetxattr lstat mkdir mkdirat mknod mknodat mmap munmap newfstatat open openat openat2 readlink readlinkat removexattr rename renameat renameat2 rmdir setxattr stat statfs statx symlink symlinkat truncate unlink unlinkat utime utimensat utimes"),
+    // Waiting for events on files
+    ("@io-event", "epoll_create epoll_create1 epoll_ctl epoll_pwait epoll_pwait2 epoll_wait eventfd eventfd2 poll ppoll pselect6 select"),
+    // Pipes, shared memory, semaphores and message queues
+    ("@ipc", "memfd_create mq_getsetattr mq_notify mq_open mq_timedreceive mq_timedsend mq_unlink msgctl msgget msgrcv msgsnd pipe pipe2 process_vm_readv process_vm_writev semctl semget semop semtimedop shmat shmctl shmdt shmget"),
+    // Sockets
+    ("@network-io", "accept accept4 bind connect getpeername getsockname getsockopt listen recvfrom recvmmsg recvmsg sendmmsg sendmsg sendto setsockopt shutdown socket socketpair"),
+    // Creating, signalling and waiting for processes
+    ("@process", "capget clone clone3 execve execveat fork getrusage kill pidfd_open pidfd_send_signal prctl rt_sigqueueinfo rt_tgsigqueueinfo setns tgkill times tkill unshare vfork wait4 waitid"),
+    // Handling signals
+    ("@signal", "rt_sigaction rt_sigpending rt_sigprocmask rt_sigsuspend rt_sigtimedwait sigaltstack signalfd signalfd4"),
+    // Timers and alarms
+    ("@timer", "alarm getitimer setitimer timer_create timer_delete timer_getoverrun timer_gettime timer_settime timerfd_create timerfd_gettime timerfd_settime"),
+    // Changing file owners
+    ("@chown", "chown fchown fchownat lchown"),
+    // Setting the system clock
+    ("@clock", "adjtimex clock_adjtime clock_settime settimeofday"),
+    // Tracing and inspecting other processes
+    ("@debug", "kcmp lookup_dcookie perf_event_open pidfd_getfd process_madvise process_vm_readv process_vm_writev ptrace"),
+    // Loading kernel modules
+    ("@module", "delete_module finit_module init_module"),
+    // Mounting filesystems
+    ("@mount", "chroot fsconfig fsmount fsopen fspick mount mount_setattr move_mount open_tree pivot_root umount2"),
+    // Direct hardware access
+    ("@raw-io", "ioperm iopl"),
+    // Rebooting the system
+    ("@reboot", "kexec_file_load kexec_load reboot"),
+    // Enabling swap
+    ("@swap", "swapoff swapon"),
+    // Calls which need privileges
+    ("@privileged", "@chown @clock @module @mount @raw-io @reboot @swap _sysctl acct bpf capset fanotify_init nfsservctl open_by_handle_at quotactl sethostname setdomainname setfsgid setfsuid setgid setgroups setregid setresgid setresuid setreuid setuid vhangup"),
+];
+
+/// System call numbers by name
+#[cfg(target_arch = "x86_64")]
+const SYSCALLS: &[(&str, u32)] = &[
+    ("read", 0),
+    ("write", 1),
+    ("open", 2),
+    ("close", 3),
+    ("stat", 4),
+    ("fstat", 5),
+    ("lstat", 6),
+    ("poll", 7),
+    ("lseek", 8),
+    ("mmap", 9),
+    ("mprotect", 10),
+    ("munmap", 11),
+    ("brk", 12),
+    ("rt_sigaction", 13),
+    ("rt_sigprocmask", 14),
+    ("rt_sigreturn", 15),
+    ("ioctl", 16),
+    ("pread64", 17),
+    ("pwrite64", 18),
+    ("readv", 19),
+    ("writev", 20),
+    ("access", 21),
+    ("pipe", 22),
+    ("select", 23),
+    ("sched_yield", 24),
+    ("mremap", 25),
+    ("msync", 26),
+    ("mincore", 27),
+    ("madvise", 28),
+    ("shmget", 29),
+    ("shmat", 30),
+    ("shmctl", 31),
+    ("dup", 32),
+    ("dup2", 33),
+    ("pause", 34),
+    ("nanosleep", 35),
+    ("getitimer", 36),
+    ("alarm", 37),
+    ("setitimer", 38),
+    ("getpid", 39),
+    ("sendfile", 40),
+    ("socket", 41),
+    ("connect", 42),
+    ("accept", 43),
+    ("sendto", 44),
+    ("recvfrom", 45),
+    ("sendmsg", 46),
+    ("recvmsg", 47),
+    ("shutdown", 48),
+    ("bind", 49),
+    ("listen", 50),
+    ("getsockname", 51),
+    ("getpeername", 52),
+    ("socketpair", 53),
+    ("setsockopt", 54),
+    ("getsockopt", 55),
+    ("clone", 56),
+    ("fork", 57),
+    ("vfork", 58),
+    ("execve", 59),
+    ("exit", 60),
+    ("wait4", 61),
+    ("kill", 62),
+    ("uname", 63),
+    ("semget", 64),
+    ("semop", 65),
+    ("semctl", 66),
+    ("shmdt", 67),
+    ("msgget", 68),
+    ("msgsnd", 69),
+    ("msgrcv", 70),
+    ("msgctl", 71),
+    ("fcntl", 72),
+    ("flock", 73),
+    ("fsync", 74),
+    ("fdatasync", 75),
+    ("truncate", 76),
+    ("ftruncate", 77),
+    ("getdents", 78),
+    ("getcwd", 79),
+    ("chdir", 80),
+    ("fchdir", 81),
+    ("rename", 82),
+    ("mkdir", 83),
+    ("rmdir", 84),
+    ("creat", 85),
+    ("link", 86),
+    ("unlink", 87),
+    ("symlink", 88),
+    ("readlink", 89),
+    ("chmod", 90),
+    ("fchmod", 91),
+    ("chown", 92),
+    ("fchown", 93),
+    ("lchown", 94),
+    ("umask", 95),
+    ("gettimeofday", 96),
+    ("getrlimit", 97),
+    ("getrusage", 98),
+    ("sysinfo", 99),
+    ("times", 100),
+    ("ptrace", 101),
+    ("getuid", 102),
+    ("syslog", 103),
+    ("getgid", 104),
+    ("setuid", 105),
+    ("setgid", 106),
+    ("geteuid", 107),
+    ("getegid", 108),
+    ("setpgid", 109),
+    ("getppid", 110),
+    ("getpgrp", 111),
+    ("setsid", 112),
+    ("setreuid", 113),
+    ("setregid", 114),
+    ("getgroups", 115),
+    ("setgroups", 116),
+    ("setresuid", 117),
+    ("getresuid", 118),
+    ("setresgid", 119),
+    ("getresgid", 120),
+    ("getpgid", 121),
+    ("setfsuid", 122),
+    ("setfsgid", 123),
+    ("getsid", 124),
+    ("capget", 125),
+    ("capset", 126),
+    ("rt_sigpending", 127),
+    ("rt_sigtimedwait", 128),
+    ("rt_sigqueueinfo", 129),
+    ("rt_sigsuspend", 130),
+    ("sigaltstack", 131),
+    ("utime", 132),
+    ("mknod", 133),
+    ("uselib", 134),
+    ("personality", 135),
+    ("ustat", 136),
+    ("statfs", 137),
+    ("fstatfs", 138),
+    ("sysfs", 139),
+    ("getpriority", 140),
+    ("setpriority", 141),
+    ("sched_setparam", 142),
+    ("sched_getparam", 143),
+    ("sched_setscheduler", 144),
+    ("sched_getscheduler", 145),
+    ("sched_get_priority_max", 146),
+    ("sched_get_priority_min", 147),
+    ("sched_rr_get_interval", 148),
+    ("mlock", 149),
+    ("munlock", 150),
+    ("mlockall", 151),
+    ("munlockall", 152),
+    ("vhangup", 153),
+    ("modify_ldt", 154),
+    ("pivot_root", 155),
+    ("_sysctl", 156),
+    ("prctl", 157),
+    ("arch_prctl", 158),
+    ("adjtimex", 159),
+    ("setrlimit", 160),
+    ("chroot", 161),
+    ("sync", 162),
+    ("acct", 163),
+    ("settimeofday", 164),
+    ("mount", 165),
+    ("umount2", 166),
+    ("swapon", 167),
+    ("swapoff", 168),
+    ("reboot", 169),
+    ("sethostname", 170),
+    ("setdomainname", 171),
+    ("iopl", 172),
+    ("ioperm", 173),
+    ("create_module", 174),
+    ("init_module", 175),
+    ("delete_module", 176),
+    ("get_kernel_syms", 177),
+    ("query_module", 178),
+    ("quotactl", 179),
+    ("nfsservctl", 180),
+    ("getpmsg", 181),
+    ("putpmsg", 182),
+    ("afs_syscall", 183),
+    ("tuxcall", 184),
+    ("security", 185),
+    ("gettid", 186),
+    ("readahead", 187),
+    ("setxattr", 188),
+    ("lsetxattr", 189),
+    ("fsetxattr", 190),
+    ("getxattr", 191),
+    ("lgetxattr", 192),
+    ("fgetxattr", 193),
+    ("listxattr", 194),
+    ("llistxattr", 195),
+    ("flistxattr", 196),
+    ("removexattr", 197),
+    ("lremovexattr", 198),
+    ("fremovexattr", 199),
+    ("tkill", 200),
+    ("time", 201),
+    ("futex", 202),
+    ("sched_setaffinity", 203),
+    ("sched_getaffinity", 204),
+    ("set_thread_area", 205),
+    ("io_setup", 206),
+    ("io_destroy", 207),
+    ("io_getevents", 208),
+    ("io_submit", 209),
+    ("io_cancel", 210),
+    ("get_thread_area", 211),
+    ("lookup_dcookie", 212),
+    ("epoll_create", 213),
+    ("epoll_ctl_old", 214),
+    ("epoll_wait_old", 215),
+    ("remap_file_pages", 216),
+    ("getdents64", 217),
+    ("set_tid_address", 218),
+    ("restart_syscall", 219),
+    ("semtimedop", 220),
+    ("fadvise64", 221),
+    ("timer_create", 222),
+    ("timer_settime", 223),
+    ("timer_gettime", 224),
+    ("timer_getoverrun", 225),
+    ("timer_delete", 226),
+    ("clock_settime", 227),
+    ("clock_gettime", 228),
+    ("clock_getres", 229),
+    ("clock_nanosleep", 230),
+    ("exit_group", 231),
+    ("epoll_wait", 232),
+    ("epoll_ctl", 233),
+    ("tgkill", 234),
+    ("utimes", 235),
+    ("vserver", 236),
+    ("mbind", 237),
+    ("set_mempolicy", 238),
+    ("get_mempolicy", 239),
+    ("mq_open", 240),
+    ("mq_unlink", 241),
+    ("mq_timedsend", 242),
+    ("mq_timedreceive", 243),
+    ("mq_notify", 244),
+    ("mq_getsetattr", 245),
+    ("kexec_load", 246),
+    ("waitid", 247),
+    ("add_key", 248),
+    ("request_key", 249),
+    ("keyctl", 250),
+    ("ioprio_set", 251),
+    ("ioprio_get", 252),
+    ("inotify_init", 253),
+    ("inotify_add_watch", 254),
+    ("inotify_rm_watch", 255),
+    ("migrate_pages", 256),
+    ("openat", 257),
+    ("mkdirat", 258),
+    ("mknodat", 259),
+    ("fchownat", 260),
+    ("futimesat", 261),
+    ("newfstatat", 262),
+    ("unlinkat", 263),
+    ("renameat", 264),
+    ("linkat", 265),
+    ("symlinkat", 266),
+    ("readlinkat", 267),
+    ("fchmodat", 268),
+    ("faccessat", 269),
+    ("pselect6", 270),
+    ("ppoll", 271),
+    ("unshare", 272),
+    ("set_robust_list", 273),
+    ("get_robust_list", 274),
+    ("splice", 275),
+    ("tee", 276),
+    ("sync_file_range", 277),
+    ("vmsplice", 278),
+    ("move_pages", 279),
+    ("utimensat", 280),
+    ("epoll_pwait", 281),
+    ("signalfd", 282),
+    ("timerfd_create", 283),
+    ("eventfd", 284),
+    ("fallocate", 285),
+    ("timerfd_settime", 286),
+    ("timerfd_gettime", 287),
+    ("accept4", 288),
+    ("signalfd4", 289),
+    ("eventfd2", 290),
+    ("epoll_create1", 291),
+    ("dup3", 292),
+    ("pipe2", 293),
+    ("inotify_init1", 294),
+    ("preadv", 295),
+    ("pwritev", 296),
+    ("rt_tgsigqueueinfo", 297),
+    ("perf_event_open", 298),
+    ("recvmmsg", 299),
+    ("fanotify_init", 300),
+    ("fanotify_mark", 301),
+    ("prlimit64", 302),
+    ("name_to_handle_at", 303),
+    ("open_by_handle_at", 304),
+    ("clock_adjtime", 305),
+    ("syncfs", 306),
+    ("sendmmsg", 307),
+    ("setns", 308),
+    ("getcpu", 309),
+    ("process_vm_readv", 310),
+    ("process_vm_writev", 311),
+    ("kcmp", 312),
+    ("finit_module", 313),
+    ("sched_setattr", 314),
+    ("sched_getattr", 315),
+    ("renameat2", 316),
+    ("seccomp", 317),
+    ("getrandom", 318),
+    ("memfd_create", 319),
+    ("kexec_file_load", 320),
+    ("bpf", 321),
+    ("execveat", 322),
+    ("userfaultfd", 323),
+    ("membarrier", 324),
+    ("mlock2", 325),
+    ("copy_file_range", 326),
+    ("preadv2", 327),
+    ("pwritev2", 328),
+    ("pkey_mprotect", 329),
+    ("pkey_alloc", 330),
+    ("pkey_free", 331),
+    ("statx", 332),
+    ("io_pgetevents", 333),
+    ("rseq", 334),
+    ("pidfd_send_signal", 424),
+    ("io_uring_setup", 425),
+    ("io_uring_enter", 426),
+    ("io_uring_register", 427),
+    ("open_tree", 428),
+    ("move_mount", 429),
+    ("fsopen", 430),
+    ("fsconfig", 431),
+    ("fsmount", 432),
+    ("fspick", 433),
+    ("pidfd_open", 434),
+    ("clone3", 435),
+    ("close_range", 436),
+    ("openat2", 437),
+    ("pidfd_getfd", 438),
+    ("faccessat2", 439),
+    ("process_madvise", 440),
+    ("epoll_pwait2", 441),
+    ("mount_setattr", 442),
+    ("quotactl_fd", 443),
+    ("landlock_create_ruleset", 444),
+    ("landlock_add_rule", 445),
+    ("landlock_restrict_self", 446),
+    ("memfd_secret", 447),
+    ("process_mrelease", 448),
+    ("futex_waitv", 449),
+    ("set_mempolicy_home_node", 450),
+];
+
+#[cfg(not(target_arch = "x86_64"))]
+const SYSCALLS: &[(&str, u32)] = &[];
+
+/// What happens to a process making a filtered system call
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Kill,
+    Errno(u32),
+}
+
+impl Action {
+    fn ret(self) -> u32 {
+        match self {
+            Action::Kill => SECCOMP_RET_KILL_PROCESS,
+            Action::Errno(errno) => SECCOMP_RET_ERRNO | errno & SECCOMP_RET_DATA,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: libc::c_ushort,
+    filter: *const SockFilter,
+}
+
+/// A compiled seccomp-BPF program
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    program: Vec<SockFilter>,
+}
+
+impl Filter {
+    /// Builds a filter which either allows only `syscalls` or denies
+    /// only them, taking `action` on any call not allowed
+    pub fn new(allow: bool, syscalls: &[u32], action: Action) -> Result<Filter, String> {
+        let arch = AUDIT_ARCH.ok_or_else(|| UNSUPPORTED.to_owned())?;
+        let stmt = |code, k| SockFilter {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        };
+        let jump = |code, k, jt, jf| SockFilter { code, jt, jf, k };
+        let (matched, unmatched) = if allow {
+            (SECCOMP_RET_ALLOW, action.ret())
+        } else {
+            (action.ret(), SECCOMP_RET_ALLOW)
+        };
+        let mut program = vec![
+            // calls made with another architecture's numbering are
+            // meaningless
+            stmt(BPF_LD_W_ABS, DATA_ARCH),
+            jump(BPF_JMP_JEQ_K, arch, 1, 0),
+            stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+            stmt(BPF_LD_W_ABS, DATA_NR),
+            jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1),
+            stmt(BPF_RET_K, action.ret()),
+        ];
+        for &nr in syscalls {
+            program.push(jump(BPF_JMP_JEQ_K, nr, 0, 1));
+            program.push(stmt(BPF_RET_K, matched));
+        }
+        program.push(stmt(BPF_RET_K, unmatched));
+        Ok(Filter { program })
+    }
+
+    /// Installs the filter in the calling process
+    pub fn install(&self) -> io::Result<()> {
+        let prog = SockFprog {
+            len: self.program.len() as libc::c_ushort,
+            filter: self.program.as_ptr(),
+        };
+        // unprivileged processes may only install filters once they
+        // can't gain privileges
+        let failed = unsafe {
+            libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == -1
+                || libc::prctl(
+                    libc::PR_SET_SECCOMP,
+                    libc::SECCOMP_MODE_FILTER,
+                    &prog as *const SockFprog,
+                ) == -1
+        };
+        if failed {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Parses system call and group names into a sorted list of system
+/// call numbers
+pub fn parse_syscalls(names: &[String]) -> Result<Vec<u32>, String> {
+    if AUDIT_ARCH.is_none() {
+        return Err(UNSUPPORTED.to_owned());
+    }
+    let mut syscalls = Vec::new();
+    for name in names {
+        add_syscalls(name, &mut syscalls)?;
+    }
+    syscalls.sort();
+    syscalls.dedup();
+    Ok(syscalls)
+}
+
+fn add_syscalls(name: &str, syscalls: &mut Vec<u32>) -> Result<(), String> {
+    if name.starts_with('@') {
+        let group = GROUPS
+            .iter()
+            .find(|(group, _)| *group == name)
+            .ok_or_else(|| format!("No such system call group \"{}\"", name))?;
+        group
+            .1
+            .split_whitespace()
+            .try_for_each(|member| add_syscalls(member, syscalls))
+    } else {
+        SYSCALLS
+            .iter()
+            .find(|(syscall, _)| *syscall == name)
+            .map(|&(_, nr)| syscalls.push(nr))
+            .ok_or_else(|| format!("No such system call \"{}\"", name))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_syscalls, Action, Filter, GROUPS};
+    use std::os::unix::process::ExitStatusExt;
+    use testing;
+
+    fn parse(names: &[&str]) -> Result<Vec<u32>, String> {
+        parse_syscalls(&names.iter().map(|n| n.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_parse_syscalls() {
+        assert_eq!(parse(&["write", "read", "read"]), Ok(vec![0, 1]));
+        assert_eq!(parse(&["@raw-io"]), Ok(vec![172, 173]));
+        assert!(parse(&["@privileged"]).unwrap().contains(&165));
+        assert!(parse(&["fly"]).is_err());
+        assert!(parse(&["@fly"]).is_err());
+        // every group's members exist
+        for (group, _) in GROUPS {
+            assert!(parse(&[group]).is_ok(), "{}", group);
+        }
+    }
+
+    #[test]
+    fn test_install() {
+        let run = |allow, names: &[&str], action, script: &str| {
+            let filter = Filter::new(allow, &parse(names).unwrap(), action).unwrap();
+            testing::sh(script, move || filter.install())
+        };
+
+        let output = run(false, &["mkdir", "mkdirat"], Action::Errno(13), "mkdir /; echo $?");
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n");
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Permission denied"));
+
+        let output = run(false, &["uname"], Action::Kill, "exec uname");
+        assert_eq!(output.status.signal(), Some(::libc::SIGSYS));
+
+        let output = run(
+            true,
+            &["@default", "@basic-io", "@file-system", "@process", "@signal", "ioctl"],
+            Action::Kill,
+            "echo ok",
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "ok\n");
+    }
+}
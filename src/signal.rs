@@ -42,6 +42,8 @@ pub fn recv_signals() -> cc::Receiver<i32> {
         signal_hook::SIGINT,
         signal_hook::SIGTERM,
         signal_hook::SIGCHLD,
+        signal_hook::SIGUSR1,
+        signal_hook::SIGUSR2,
     ];
     let signals = signal_hook::iterator::Signals::new(&signals).unwrap();
     let (sig_send, sig_recv) = cc::unbounded();
@@ -56,3 +58,58 @@ pub fn recv_signals() -> cc::Receiver<i32> {
 pub fn signal(pid: u32, sig: i32) {
     unsafe { libc::kill(pid as libc::pid_t, sig) };
 }
+
+const SIGNALS: &[(&str, libc::c_int)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ILL", libc::SIGILL),
+    ("TRAP", libc::SIGTRAP),
+    ("ABRT", libc::SIGABRT),
+    ("BUS", libc::SIGBUS),
+    ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("SEGV", libc::SIGSEGV),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("STKFLT", libc::SIGSTKFLT),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("URG", libc::SIGURG),
+    ("XCPU", libc::SIGXCPU),
+    ("XFSZ", libc::SIGXFSZ),
+    ("VTALRM", libc::SIGVTALRM),
+    ("PROF", libc::SIGPROF),
+    ("WINCH", libc::SIGWINCH),
+    ("IO", libc::SIGIO),
+    ("PWR", libc::SIGPWR),
+    ("SYS", libc::SIGSYS),
+];
+
+/// Finds a signal by name, eg. `"SIGTERM"` or `"term"`
+pub fn parse_signal(name: &str) -> Option<libc::c_int> {
+    let upper = name.to_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+    SIGNALS.iter().find(|(n, _)| *n == name).map(|(_, s)| *s)
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_signal;
+    use libc;
+
+    #[test]
+    fn test_parse_signal() {
+        assert_eq!(parse_signal("SIGTERM"), Some(libc::SIGTERM));
+        assert_eq!(parse_signal("hup"), Some(libc::SIGHUP));
+        assert_eq!(parse_signal("SIGSIGTERM"), None);
+        assert_eq!(parse_signal("3"), None);
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright (c) 2018, [Ribose Inc](https://www.ribose.com).
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// ``AS IS'' AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crossbeam_channel as cc;
+use libc;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::iter;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::ptr;
+use std::thread;
+
+/// The first fd passed to an application, after its standard streams
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Room for the digits of a pid in `LISTEN_PID`
+const PID_DIGITS: usize = 10;
+
+extern "C" {
+    static mut environ: *const *const libc::c_char;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+#[derive(Debug)]
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// A listening socket held open by Riffol for as long as it runs so
+/// it outlives any application it is passed to
+#[derive(Debug)]
+pub struct Socket {
+    pub name: String,
+    listener: Listener,
+}
+
+impl Socket {
+    /// Binds a listening socket. A socket file left behind at a Unix
+    /// address is replaced.
+    pub fn bind(name: &str, address: &Address) -> io::Result<Socket> {
+        let listener = match address {
+            Address::Tcp(addr) => Listener::Tcp(TcpListener::bind(addr)?),
+            Address::Unix(path) => {
+                match fs::symlink_metadata(path) {
+                    Ok(ref m) if m.file_type().is_socket() => fs::remove_file(path)?,
+                    _ => (),
+                }
+                Listener::Unix(UnixListener::bind(path)?)
+            }
+        };
+        Ok(Socket {
+            name: name.to_owned(),
+            listener,
+        })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        match &self.listener {
+            Listener::Tcp(l) => l.as_raw_fd(),
+            Listener::Unix(l) => l.as_raw_fd(),
+        }
+    }
+}
+
+/// Passes sockets to an application the way systemd does: as fds
+/// from 3, with `LISTEN_FDS` their number, `LISTEN_FDNAMES` their
+/// names and `LISTEN_PID` the application's pid. Everything is
+/// prepared before fork so the application's environment, which has
+/// to include its pid, can be set without allocating.
+///
+/// Until it's dropped an `Activation` holds any free fds where the
+/// sockets are going so nothing opened in the meantime, in particular
+/// the pipe `Command` reports exec failures through, can end up there.
+pub struct Activation {
+    fds: Vec<RawFd>,
+    moved: Vec<RawFd>,
+    reserved: Vec<File>,
+    vars: Vec<Vec<u8>>,
+    envp: Vec<*const libc::c_char>,
+}
+
+// the pointers in `envp` are into `vars`, whose buffers never move
+unsafe impl Send for Activation {}
+unsafe impl Sync for Activation {}
+
+impl Activation {
+    pub fn new<'a, I>(sockets: I, env: &HashMap<String, String>) -> Activation
+    where
+        I: Iterator<Item = &'a Socket>,
+    {
+        let (fds, names): (Vec<_>, Vec<_>) = sockets.map(|s| (s.fd(), s.name.as_str())).unzip();
+        let mut vars = env
+            .iter()
+            .filter(|(k, _)| !k.starts_with("LISTEN_"))
+            .map(|(k, v)| format!("{}={}\0", k, v).into_bytes())
+            .collect::<Vec<_>>();
+        vars.push(format!("LISTEN_FDS={}\0", fds.len()).into_bytes());
+        vars.push(format!("LISTEN_FDNAMES={}\0", names.join(":")).into_bytes());
+        let mut pid = b"LISTEN_PID=".to_vec();
+        pid.resize(pid.len() + PID_DIGITS + 1, 0);
+        vars.push(pid);
+        let envp = vars
+            .iter()
+            .map(|v| v.as_ptr() as *const libc::c_char)
+            .chain(iter::once(ptr::null()))
+            .collect();
+        Activation {
+            moved: fds.clone(),
+            reserved: reserve(fds.len() as RawFd),
+            fds,
+            vars,
+            envp,
+        }
+    }
+
+    /// Sets up the calling process's fds and environment. Intended to
+    /// be called just before exec.
+    pub fn apply(&mut self) -> io::Result<()> {
+        let count = self.fds.len() as RawFd;
+        // anything but the sockets and reserved fds in the way would
+        // be lost
+        let in_use = (LISTEN_FDS_START..LISTEN_FDS_START + count).any(|fd| {
+            (unsafe { libc::fcntl(fd, libc::F_GETFD) }) != -1
+                && !self.fds.contains(&fd)
+                && !self.reserved.iter().any(|f| f.as_raw_fd() == fd)
+        });
+        if in_use {
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+        }
+        // first move the fds clear of where they are going so none is
+        // overwritten before it has been moved
+        for (fd, moved) in self.fds.iter().zip(self.moved.iter_mut()) {
+            *moved = unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, LISTEN_FDS_START + count) };
+            if *moved == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        for (target, &fd) in (LISTEN_FDS_START..).zip(self.moved.iter()) {
+            if unsafe { libc::dup2(fd, target) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        let pid = self.vars.last_mut().unwrap();
+        let start = pid.len() - PID_DIGITS - 1;
+        let mut digits = [0u8; PID_DIGITS];
+        let mut n = unsafe { libc::getpid() } as u32;
+        let mut i = digits.len();
+        loop {
+            i -= 1;
+            digits[i] = b'0' + (n % 10) as u8;
+            n /= 10;
+            if n == 0 {
+                break;
+            }
+        }
+        pid[start..start + PID_DIGITS - i].copy_from_slice(&digits[i..]);
+        unsafe { environ = self.envp.as_ptr() };
+        Ok(())
+    }
+}
+
+/// Opens `/dev/null` on each free fd `count` from the first passed
+/// to an application
+fn reserve(count: RawFd) -> Vec<File> {
+    let mut reserved = Vec::new();
+    // the lowest free fd is always used
+    while let Ok(file) = File::open("/dev/null") {
+        if file.as_raw_fd() >= LISTEN_FDS_START + count {
+            break;
+        }
+        reserved.push(file);
+    }
+    reserved
+}
+
+/// Watches `fds` for connections on behalf of the application at
+/// `idx`, which is sent to `activate` when one arrives. Watching
+/// resumes once the returned sender is sent to, when the application
+/// is no longer running.
+pub fn watch(fds: Vec<RawFd>, idx: usize, activate: cc::Sender<usize>) -> cc::Sender<()> {
+    let (rearm_send, rearm_recv) = cc::unbounded();
+    thread::spawn(move || {
+        let mut pollfds = fds
+            .iter()
+            .map(|&fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            }).collect::<Vec<_>>();
+        loop {
+            let ready = unsafe {
+                libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, -1)
+            };
+            if ready == -1 {
+                if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                warn!("Can't watch sockets: {}", io::Error::last_os_error());
+                return;
+            }
+            activate.send(idx);
+            if rearm_recv.recv().is_none() {
+                return;
+            }
+        }
+    });
+    rearm_send
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Activation, Address, Socket};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+    use testing;
+
+    #[test]
+    fn test_activation() {
+        let dir = ::std::env::temp_dir().join(format!("riffol-socket-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let unix = Address::Unix(dir.join("sock"));
+        let sockets = [
+            Socket::bind("tcp", &Address::Tcp("127.0.0.1:0".parse().unwrap())).unwrap(),
+            Socket::bind("unix", &unix).unwrap(),
+        ];
+        // a socket file left behind is replaced
+        Socket::bind("unix", &unix).unwrap();
+
+        let mut env = HashMap::new();
+        env.insert("VAR".to_owned(), "value".to_owned());
+        env.insert("LISTEN_FDS".to_owned(), "9".to_owned());
+        let mut activation = Activation::new(sockets.iter(), &env);
+        let output = testing::sh(
+            "echo $VAR $LISTEN_FDS $LISTEN_FDNAMES; \
+             test \"$LISTEN_PID\" = $$ && echo pid; \
+             readlink /proc/$$/fd/3 /proc/$$/fd/4 | cut -d: -f1",
+            move || activation.apply(),
+        );
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "value 2 tcp:unix\npid\nsocket\nsocket\n"
+        );
+    }
+
+    #[test]
+    fn test_exec_failure() {
+        // enough fds that Command's error pipe would be among them
+        let socket = Socket::bind("tcp", &Address::Tcp("127.0.0.1:0".parse().unwrap())).unwrap();
+        let mut activation = Activation::new((0..64).map(|_| &socket), &HashMap::new());
+        let mut command = Command::new("/nonexistent");
+        unsafe { command.pre_exec(move || activation.apply()) };
+        assert!(command.spawn().is_err());
+    }
+}
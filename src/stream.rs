@@ -25,13 +25,22 @@ use crossbeam_channel as cc;
 use mio::unix::{EventedFd, UnixReady};
 use mio::{Events, Poll, PollOpt, Ready, Token};
 use nix::fcntl::{fcntl, FcntlArg::F_SETFL, OFlag};
+use regex::Regex;
 use slab::Slab;
-use std::fs::{File, OpenOptions};
-use std::io::{self, BufRead, BufReader, Write};
+use libc;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CString;
+use std::fs::{File, OpenOptions, Permissions};
+use std::io::{self, Read, Write};
 use std::net::SocketAddr;
-use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::ptr;
+use std::str;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use syslog::{self, Formatter3164, Logger, LoggerBackend, Severity::*};
 
 /// Address used for various flavours of Syslog.
@@ -45,48 +54,894 @@ pub enum Address {
     Unix(Option<String>),
 }
 
+/// Default socket for the journald native protocol
+pub const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
 /// Stream descriptions. Currently supported are `Syslog`
-/// (TCP/UDP/Unix) and files
+/// (TCP/UDP/Unix), systemd-journald, files and Riffol's own standard
+/// output
 #[derive(Debug, Clone)]
 pub enum Stream {
+    Console,
     File {
         filename: String,
+        /// Permissions given to the file if it's created, otherwise
+        /// those allowed by Riffol's umask
+        mode: Option<u32>,
+        /// Owner given to the file if it's created. `!0` leaves the
+        /// user or group unchanged.
+        owner: Option<(libc::uid_t, libc::gid_t)>,
     },
     Syslog {
         address: Address,
         facility: syslog::Facility,
         severity: u32, // syslog::Severity doesn't implement Debug,
+        rules: Vec<SeverityRule>,
+    },
+    Journal {
+        socket: String,
+        identifier: Option<String>,
+        facility: syslog::Facility,
+        severity: u32,
+        rules: Vec<SeverityRule>,
     },
-    #[cfg(test)]
-    Stdout,
 }
 
-/// `Connection` is used to associate a source `fd` with a `Stream` description
+/// Identifies the application, and which of its standard streams, a
+/// source `fd` belongs to
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub app: String,
+    pub pid: u32,
+    pub name: &'static str,
+}
+
+/// Rules used to find the severity of an individual line written to
+/// syslog. Rules are tried in order and the first match wins. Lines
+/// matching no rule are logged with the stream's `severity`.
+#[derive(Debug, Clone)]
+pub enum SeverityRule {
+    /// Kernel style `<N>` prefix. The prefix is removed from the line.
+    Prefix,
+    /// `key=level` pair, eg. `level=warn`
+    Key(String),
+    /// Lines matching the regex have the given severity
+    Pattern(Regex, u32),
+}
+
+impl SeverityRule {
+    fn severity<'a>(&self, line: &'a str) -> Option<(u32, &'a str)> {
+        match self {
+            SeverityRule::Prefix => {
+                let bytes = line.as_bytes();
+                if bytes.len() >= 3
+                    && bytes[0] == b'<'
+                    && bytes[1] >= b'0'
+                    && bytes[1] <= b'7'
+                    && bytes[2] == b'>'
+                {
+                    Some((u32::from(bytes[1] - b'0'), &line[3..]))
+                } else {
+                    None
+                }
+            }
+            SeverityRule::Key(key) => line
+                .split_whitespace()
+                .filter_map(|pair| {
+                    let mut kv = pair.splitn(2, '=');
+                    match (kv.next(), kv.next()) {
+                        (Some(k), Some(v)) if k == key => Some(v.trim_matches('"')),
+                        _ => None,
+                    }
+                }).next()
+                .and_then(severity_from_name)
+                .map(|s| (s, line)),
+            SeverityRule::Pattern(regex, severity) => if regex.is_match(line) {
+                Some((*severity, line))
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// Converts a commonly used level name into a syslog severity
+fn severity_from_name(name: &str) -> Option<u32> {
+    match name.to_lowercase().as_ref() {
+        "emerg" | "emergency" | "panic" => Some(LOG_EMERG as u32),
+        "alert" => Some(LOG_ALERT as u32),
+        "crit" | "critical" | "fatal" => Some(LOG_CRIT as u32),
+        "err" | "error" => Some(LOG_ERR as u32),
+        "warn" | "warning" => Some(LOG_WARNING as u32),
+        "notice" => Some(LOG_NOTICE as u32),
+        "info" | "information" => Some(LOG_INFO as u32),
+        "debug" | "trace" => Some(LOG_DEBUG as u32),
+        _ => None,
+    }
+}
+
+/// Applies `rules` to `line` returning the severity to use and the
+/// line to log
+fn detect_severity<'a>(rules: &[SeverityRule], severity: u32, line: &'a str) -> (u32, &'a str) {
+    rules
+        .iter()
+        .filter_map(|rule| rule.severity(line))
+        .next()
+        .unwrap_or((severity, line))
+}
+
+/// Rule used to decide whether a line continues the previous one
+#[derive(Debug, Clone)]
+pub enum Continuation {
+    /// Lines matching the regex are continuations
+    Pattern(Regex),
+    /// Lines starting with whitespace are continuations
+    Indented,
+}
+
+impl Continuation {
+    fn matches(&self, line: &str) -> bool {
+        match self {
+            Continuation::Pattern(regex) => regex.is_match(line),
+            Continuation::Indented => line.starts_with(|c: char| c.is_whitespace()),
+        }
+    }
+}
+
+/// Describes how consecutive lines are merged into a single event
+/// (eg. stack traces). A merged event is written when a line which
+/// isn't a continuation arrives, when no line has arrived for
+/// `timeout` or when the source closes.
+#[derive(Debug, Clone)]
+pub struct Multiline {
+    pub continuation: Continuation,
+    pub timeout: Duration,
+}
+
+/// What to do with a line when a destination's queue is full
+#[derive(Debug, Clone, PartialEq)]
+pub enum Overflow {
+    /// Discard the oldest queued line to make room
+    DropOldest,
+    /// Discard the new line
+    DropNewest,
+    /// Wait for room. This stalls all streams.
+    Block,
+}
+
+/// Describes the queue of lines waiting to be written to a destination
+#[derive(Debug, Clone)]
+pub struct Queue {
+    pub size: usize,
+    pub overflow: Overflow,
+}
+
+impl Default for Queue {
+    fn default() -> Queue {
+        Queue {
+            size: 1024,
+            overflow: Overflow::DropOldest,
+        }
+    }
+}
+
+/// A `Stream` along with any processing applied to lines before
+/// they are written to it
+#[derive(Debug, Clone)]
+pub struct Destination {
+    pub stream: Stream,
+    pub multiline: Option<Multiline>,
+    pub queue: Queue,
+    pub rate_limit: Option<RateLimit>,
+    /// `strftime` format of a timestamp prefixed to each line
+    pub timestamp: Option<CString>,
+}
+
+impl Destination {
+    /// A destination without any processing options
+    pub fn new(stream: Stream) -> Destination {
+        Destination {
+            stream,
+            multiline: None,
+            queue: Queue::default(),
+            rate_limit: None,
+            timestamp: None,
+        }
+    }
+}
+
+/// Limits the number of lines and/or bytes written to a destination
+/// in each `interval`. Up to `burst` lines may be written at once if
+/// earlier intervals were quiet.
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    pub lines: Option<u64>,
+    pub bytes: Option<u64>,
+    pub interval: Duration,
+    pub burst: Option<u64>,
+}
+
+/// Token bucket refilled at `rate` tokens per second up to `capacity`
+struct Bucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+}
+
+impl Bucket {
+    fn new(capacity: u64, amount: u64, interval: Duration) -> Bucket {
+        let interval = interval.as_secs() as f64 + f64::from(interval.subsec_nanos()) / 1e9;
+        Bucket {
+            capacity: capacity as f64,
+            rate: amount as f64 / interval,
+            tokens: capacity as f64,
+        }
+    }
+
+    fn refill(&mut self, elapsed: Duration) {
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = self.capacity.min(self.tokens + elapsed * self.rate);
+    }
+}
+
+/// Applies a `RateLimit` and counts the lines it suppresses
+struct Limiter {
+    interval: Duration,
+    lines: Option<Bucket>,
+    bytes: Option<Bucket>,
+    updated: Instant,
+    suppressed: u64,
+    suppressed_time: Instant,
+}
+
+impl Limiter {
+    fn new(limit: RateLimit) -> Limiter {
+        let now = Instant::now();
+        Limiter {
+            interval: limit.interval,
+            lines: limit
+                .lines
+                .map(|n| Bucket::new(limit.burst.unwrap_or(n), n, limit.interval)),
+            bytes: limit.bytes.map(|n| Bucket::new(n, n, limit.interval)),
+            updated: now,
+            suppressed: 0,
+            suppressed_time: now,
+        }
+    }
+
+    /// Returns `true` if a line of `len` bytes may be written
+    fn allow(&mut self, len: usize, now: Instant) -> bool {
+        let elapsed = now - self.updated.min(now);
+        self.updated = now;
+        self.lines.iter_mut().for_each(|b| b.refill(elapsed));
+        self.bytes.iter_mut().for_each(|b| b.refill(elapsed));
+
+        // a line longer than the bucket costs the whole bucket, or
+        // it could never be written
+        let len = self.bytes.as_ref().map_or(0.0, |b| b.capacity.min(len as f64));
+        if self.lines.iter().all(|b| b.tokens >= 1.0)
+            && self.bytes.iter().all(|b| b.tokens >= len)
+        {
+            self.lines.iter_mut().for_each(|b| b.tokens -= 1.0);
+            self.bytes.iter_mut().for_each(|b| b.tokens -= len);
+            true
+        } else {
+            self.suppressed += 1;
+            self.suppressed_time = now;
+            false
+        }
+    }
+
+    /// Returns a summary of suppressed lines if there are any and
+    /// either `force` is set or nothing has been suppressed for an
+    /// interval
+    fn summary(&mut self, now: Instant, force: bool) -> Option<String> {
+        if self.suppressed > 0 && (force || now >= self.suppressed_time + self.interval) {
+            let summary = format!("riffol: {} messages suppressed", self.suppressed);
+            self.suppressed = 0;
+            Some(summary)
+        } else {
+            None
+        }
+    }
+}
+
+extern "C" {
+    // not provided by the libc crate
+    fn strftime(
+        s: *mut libc::c_char,
+        max: libc::size_t,
+        format: *const libc::c_char,
+        tm: *const libc::tm,
+    ) -> libc::size_t;
+}
+
+/// Returns the local time formatted according to `format` (see
+/// `strftime(3)`)
+fn timestamp(format: &CString) -> String {
+    let mut buffer = [0u8; 128];
+    let len = unsafe {
+        let now = libc::time(ptr::null_mut());
+        let mut tm: libc::tm = ::std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        strftime(
+            buffer.as_mut_ptr() as *mut libc::c_char,
+            buffer.len(),
+            format.as_ptr(),
+            &tm,
+        )
+    };
+    String::from_utf8_lossy(&buffer[..len]).into_owned()
+}
+
+/// What to do with a line longer than `Capture::max_line`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Overlong {
+    /// Emit the line in `max_line` sized pieces
+    Split,
+    /// Emit the first `max_line` bytes and discard the rest
+    Truncate,
+}
+
+/// How to convert captured bytes which aren't valid UTF-8
+#[derive(Debug, Clone, PartialEq)]
+pub enum Encoding {
+    /// Replace invalid sequences with U+FFFD
+    Lossy,
+    /// Replace invalid bytes with `\xNN` escapes
+    Escape,
+}
+
+/// Describes how raw output from a source `fd` is broken into lines
+#[derive(Debug, Clone)]
+pub struct Capture {
+    pub max_line: usize,
+    pub overlong: Overlong,
+    pub encoding: Encoding,
+    /// Number of recent lines kept in the application's `History`
+    pub history: usize,
+}
+
+impl Default for Capture {
+    fn default() -> Capture {
+        Capture {
+            max_line: 4096,
+            overlong: Overlong::Split,
+            encoding: Encoding::Lossy,
+            history: 10,
+        }
+    }
+}
+
+/// Ring buffers of the most recent lines captured from each
+/// application (stdout and stderr combined), keyed by application
+/// id, along with the pids of its streams which are still open.
+/// Shared between the `Handler` and its background thread.
+///
+/// Methods which may complete pending reports return them, formatted,
+/// for the caller to log.
+#[derive(Clone, Default)]
+struct History(Arc<Mutex<HashMap<String, AppHistory>>>);
+
+#[derive(Default)]
+struct AppHistory {
+    lines: VecDeque<String>,
+    open: Vec<u32>,
+    reports: Vec<Report>,
+}
+
+/// A report waiting until the streams of `pid` close or `deadline`
+struct Report {
+    pid: Option<u32>,
+    deadline: Instant,
+    message: String,
+}
+
+impl AppHistory {
+    /// Removes the reports matching `f` and adds the recent lines to
+    /// them
+    fn take_reports<F: Fn(&Report) -> bool>(&mut self, f: F) -> Vec<String> {
+        let (due, pending) = self.reports.drain(..).partition(f);
+        self.reports = pending;
+        due.into_iter()
+            .map(|report: Report| match self.lines.len() {
+                0 => report.message,
+                _ => format!(
+                    "{}. Last output:\n{}",
+                    report.message,
+                    self.lines.iter().cloned().collect::<Vec<_>>().join("\n")
+                ),
+            }).collect()
+    }
+}
+
+/// Logs reports completed by `History`
+fn log_reports(reports: Vec<String>) {
+    reports.iter().for_each(|report| warn!("{}", report));
+}
+
+impl History {
+    fn record(&self, app: &str, size: usize, line: &str) {
+        if size > 0 {
+            let mut apps = self.0.lock().unwrap();
+            let lines = &mut apps.entry(app.to_owned()).or_default().lines;
+            while lines.len() >= size {
+                lines.pop_front();
+            }
+            lines.push_back(line.to_owned());
+        }
+    }
+
+    fn recent(&self, app: &str) -> Vec<String> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(app)
+            .map(|history| history.lines.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Forgets the recent lines, completing any pending reports first
+    fn clear(&self, app: &str) -> Vec<String> {
+        match self.0.lock().unwrap().get_mut(app) {
+            Some(history) => {
+                let reports = history.take_reports(|_| true);
+                history.lines.clear();
+                reports
+            }
+            None => Vec::new(),
+        }
+    }
+
+    fn opened(&self, app: &str, pid: u32) {
+        let mut apps = self.0.lock().unwrap();
+        apps.entry(app.to_owned()).or_default().open.push(pid);
+    }
+
+    fn closed(&self, app: &str, pid: u32) -> Vec<String> {
+        let mut apps = self.0.lock().unwrap();
+        match apps.get_mut(app) {
+            Some(history) => {
+                if let Some(i) = history.open.iter().position(|&p| p == pid) {
+                    history.open.remove(i);
+                }
+                match history.open.contains(&pid) {
+                    true => Vec::new(),
+                    false => history.take_reports(|r| r.pid == Some(pid)),
+                }
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Adds a report of `message` and the recent lines, which is
+    /// completed once the streams of `pid`, if given, have closed or
+    /// `timeout` has passed
+    fn report(&self, app: &str, pid: Option<u32>, message: String, timeout: Duration) -> Vec<String> {
+        let mut apps = self.0.lock().unwrap();
+        let history = apps.entry(app.to_owned()).or_default();
+        history.reports.push(Report {
+            pid,
+            deadline: Instant::now() + timeout,
+            message,
+        });
+        let open = history.open.clone();
+        history.take_reports(|r| r.pid.filter(|pid| open.contains(pid)).is_none())
+    }
+
+    /// Completes the reports which have waited too long
+    fn expire(&self, now: Instant) -> Vec<String> {
+        let mut apps = self.0.lock().unwrap();
+        apps.iter_mut()
+            .flat_map(|(app, history)| {
+                history
+                    .reports
+                    .iter()
+                    .filter(|r| r.deadline <= now)
+                    .filter_map(|r| r.pid)
+                    .for_each(|pid| debug!("Output of {} ({}) is still open", app, pid));
+                history.take_reports(|r| r.deadline <= now)
+            }).collect()
+    }
+}
+
+/// Accumulates bytes read from a source and breaks them into lines
+/// according to a `Capture` description.
+struct LineBuffer {
+    capture: Capture,
+    buffer: Vec<u8>,
+    discarding: bool,
+    /// Whether lines end with CRLF, as a pty's do
+    crlf: bool,
+}
+
+impl LineBuffer {
+    fn new(capture: Capture, crlf: bool) -> LineBuffer {
+        LineBuffer {
+            capture,
+            buffer: Vec::new(),
+            discarding: false,
+            crlf,
+        }
+    }
+
+    /// Appends `data` to the buffer and returns any complete lines
+    /// (without their terminating newline)
+    fn push(&mut self, data: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for &byte in data {
+            if byte == b'\n' {
+                if self.crlf && self.buffer.last() == Some(&b'\r') {
+                    self.buffer.pop();
+                }
+                if !self.discarding {
+                    lines.push(self.take(None));
+                }
+                self.buffer.clear();
+                self.discarding = false;
+            } else if !self.discarding {
+                self.buffer.push(byte);
+                if self.buffer.len() > self.capture.max_line {
+                    let max_line = self.capture.max_line;
+                    lines.push(self.take(Some(max_line)));
+                    if self.capture.overlong == Overlong::Truncate {
+                        self.buffer.clear();
+                        self.discarding = true;
+                    }
+                }
+            }
+        }
+        lines
+    }
+
+    /// Returns any trailing partial line. Used when the source closes.
+    fn flush(&mut self) -> Option<String> {
+        let line = if self.discarding || self.buffer.is_empty() {
+            None
+        } else {
+            Some(self.take(None))
+        };
+        self.buffer.clear();
+        self.discarding = false;
+        line
+    }
+
+    /// Removes up to `len` bytes (or the whole buffer) from the front
+    /// of the buffer and decodes them. When splitting, the split
+    /// point is moved back so multibyte characters aren't broken.
+    fn take(&mut self, len: Option<usize>) -> String {
+        let mut len = len.unwrap_or(self.buffer.len());
+        if let Err(e) = str::from_utf8(&self.buffer[..len]) {
+            if e.error_len().is_none() && e.valid_up_to() > 0 {
+                len = e.valid_up_to();
+            }
+        }
+        let line = decode(&self.buffer[..len], &self.capture.encoding);
+        self.buffer.drain(..len);
+        line
+    }
+}
+
+/// Converts `bytes` into a `String` using `encoding`
+fn decode(bytes: &[u8], encoding: &Encoding) -> String {
+    match encoding {
+        Encoding::Lossy => String::from_utf8_lossy(bytes).into_owned(),
+        Encoding::Escape => {
+            let mut line = String::with_capacity(bytes.len());
+            let mut bytes = bytes;
+            loop {
+                match str::from_utf8(bytes) {
+                    Ok(s) => {
+                        line.push_str(s);
+                        break;
+                    }
+                    Err(e) => {
+                        let (valid, rest) = bytes.split_at(e.valid_up_to());
+                        line.push_str(unsafe { str::from_utf8_unchecked(valid) });
+                        let bad = e.error_len().unwrap_or(rest.len());
+                        rest[..bad]
+                            .iter()
+                            .for_each(|b| line.push_str(&format!("\\x{:02x}", b)));
+                        bytes = &rest[bad..];
+                    }
+                }
+            }
+            line
+        }
+    }
+}
+
+/// Merges continuation lines into a pending event according to a
+/// `Multiline` description
+struct Merger {
+    multiline: Multiline,
+    pending: Option<(String, Instant)>,
+}
+
+impl Merger {
+    fn new(multiline: Multiline) -> Merger {
+        Merger {
+            multiline,
+            pending: None,
+        }
+    }
+
+    /// Adds `line` to the pending event if it is a continuation.
+    /// Otherwise `line` starts a new pending event and the previous
+    /// one (if any) is returned.
+    fn push(&mut self, line: &str) -> Option<String> {
+        let now = Instant::now();
+        match self.pending.take() {
+            Some((mut event, _)) if self.multiline.continuation.matches(line) => {
+                event.push('\n');
+                event.push_str(line);
+                self.pending = Some((event, now));
+                None
+            }
+            previous => {
+                self.pending = Some((line.to_owned(), now));
+                previous.map(|(event, _)| event)
+            }
+        }
+    }
+
+    /// Returns the pending event if no line has been added within
+    /// the timeout
+    fn expire(&mut self, now: Instant) -> Option<String> {
+        match self.pending {
+            Some((_, t)) if t + self.multiline.timeout <= now => self.flush(),
+            _ => None,
+        }
+    }
+
+    fn flush(&mut self) -> Option<String> {
+        self.pending.take().map(|(event, _)| event)
+    }
+}
+
+/// One destination of a `Connection`. Lines are merged (if required)
+/// by the handler thread then queued for a writer thread dedicated to
+/// the destination so a slow destination doesn't hold up the others.
+struct Sink {
+    merger: Option<Merger>,
+    limiter: Option<Limiter>,
+    timestamp: Option<CString>,
+    overflow: Overflow,
+    writer: cc::Sender<String>,
+    // kept so the oldest line can be discarded when the queue is full
+    queue: cc::Receiver<String>,
+    thread: thread::JoinHandle<()>,
+    dropped: u64,
+    reported: u64,
+    report_time: Instant,
+}
+
+impl Sink {
+    fn new(destination: Destination, source: &Source) -> Sink {
+        let (tx, rx) = cc::bounded::<String>(destination.queue.size);
+        let stream = destination.stream;
+        let source = source.clone();
+        let lines = rx.clone();
+        let thread = thread::spawn(move || {
+            for line in lines {
+                if let Err(e) = write_line(&stream, &source, &line) {
+                    warn!("Stream redirection failure ({}): {}", e, line);
+                }
+            }
+        });
+        Sink {
+            merger: destination.multiline.map(Merger::new),
+            limiter: destination.rate_limit.map(Limiter::new),
+            timestamp: destination.timestamp,
+            overflow: destination.queue.overflow,
+            writer: tx,
+            queue: rx,
+            thread,
+            dropped: 0,
+            reported: 0,
+            report_time: Instant::now(),
+        }
+    }
+
+    fn write(&mut self, line: &str) {
+        match self.merger.as_mut().map(|m| m.push(line)) {
+            Some(Some(event)) => self.emit(event),
+            Some(None) => (),
+            None => self.emit(line.to_owned()),
+        }
+    }
+
+    /// Applies any rate limit to `event` before sending it. A summary
+    /// of previously suppressed events is sent first.
+    fn emit(&mut self, event: String) {
+        let now = Instant::now();
+        if let Some(limiter) = self.limiter.as_mut() {
+            if !limiter.allow(event.len(), now) {
+                return;
+            }
+        }
+        if let Some(summary) = self.limiter.as_mut().and_then(|l| l.summary(now, true)) {
+            self.send(summary);
+        }
+        self.send(event);
+    }
+
+    /// Queues `line` for the writer thread applying the overflow
+    /// policy if the queue is full. Only this thread sends so the
+    /// queue can't fill up between checking and sending.
+    fn send(&mut self, line: String) {
+        let line = match self.timestamp.as_ref() {
+            Some(format) => format!("{} {}", timestamp(format), line),
+            None => line,
+        };
+        if self.writer.is_full() {
+            match self.overflow {
+                Overflow::DropOldest => {
+                    if self.queue.try_recv().is_some() {
+                        self.dropped += 1;
+                    }
+                }
+                Overflow::DropNewest => {
+                    self.dropped += 1;
+                    return;
+                }
+                Overflow::Block => (),
+            }
+        }
+        self.writer.send(line);
+    }
+
+    /// Writes any pending multiline event and suppressed summary
+    fn flush(&mut self) {
+        if let Some(event) = self.merger.as_mut().and_then(|m| m.flush()) {
+            self.emit(event);
+        }
+        let now = Instant::now();
+        if let Some(summary) = self.limiter.as_mut().and_then(|l| l.summary(now, true)) {
+            self.send(summary);
+        }
+        self.report();
+    }
+
+    /// Writes any multiline event which has timed out, summarises
+    /// suppressed lines once the destination has been quiet for an
+    /// interval and reports dropped lines at most once a second
+    fn expire(&mut self, now: Instant) {
+        if let Some(event) = self.merger.as_mut().and_then(|m| m.expire(now)) {
+            self.emit(event);
+        }
+        if let Some(summary) = self.limiter.as_mut().and_then(|l| l.summary(now, false)) {
+            self.send(summary);
+        }
+        if now >= self.report_time + Duration::from_secs(1) {
+            self.report();
+        }
+    }
+
+    /// Closes the queue, returning the writer thread which exits once
+    /// it has written the lines left in it
+    fn close(self) -> thread::JoinHandle<()> {
+        self.thread
+    }
+
+    fn report(&mut self) {
+        if self.dropped > self.reported {
+            warn!(
+                "Stream queue full. {} lines dropped ({} in total)",
+                self.dropped - self.reported,
+                self.dropped
+            );
+            self.reported = self.dropped;
+        }
+        self.report_time = Instant::now();
+    }
+}
+
+/// `Connection` is used to associate a source `fd` with one or more
+/// `Destination`s
 struct Connection {
-    source: BufReader<File>,
-    sink: Stream,
+    source: File,
+    lines: LineBuffer,
+    sinks: Vec<Sink>,
+    app: String,
+    pid: u32,
+    history: History,
+    history_size: usize,
 }
 
 impl Connection {
-    /// Associate `fd` with `Stream`: first ensures the `fd` is set to
-    /// non-blocking, then converts it into a `BufReader<File>`
-    fn new(fd: RawFd, stream: Stream) -> Connection {
+    /// Associate `fd` with `Destination`s: first ensures the `fd` is
+    /// set to non-blocking, then converts it into a `File`
+    fn new(
+        fd: RawFd,
+        source: &Source,
+        destinations: Vec<Destination>,
+        capture: Capture,
+        history: &History,
+    ) -> Connection {
         // set fd to non-blocking and convert to File
         fcntl(fd, F_SETFL(OFlag::O_NONBLOCK)).unwrap(); // TODO: check result
         Connection {
-            source: BufReader::new(unsafe { File::from_raw_fd(fd) }),
-            sink: stream,
+            source: unsafe { File::from_raw_fd(fd) },
+            history_size: capture.history,
+            lines: LineBuffer::new(capture, source.name == "pty"),
+            sinks: destinations
+                .into_iter()
+                .map(|d| Sink::new(d, source))
+                .collect(),
+            app: source.app.to_owned(),
+            pid: source.pid,
+            history: history.clone(),
+        }
+    }
+
+    /// Reads from the source until it would block or reaches end of
+    /// file, writing complete lines to the sinks. Returns `true` if
+    /// the source has closed.
+    fn read(&mut self) -> bool {
+        let mut data = [0u8; 4096];
+        loop {
+            match self.source.read(&mut data) {
+                Ok(0) => return true,
+                Ok(n) => {
+                    let lines = self.lines.push(&data[..n]);
+                    lines.iter().for_each(|line| self.write(line));
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return false,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                // a pty master reports EIO once the slave is closed
+                Err(ref e) if e.raw_os_error() == Some(libc::EIO) => return true,
+                Err(e) => {
+                    warn!("Stream error {}", e);
+                    return true;
+                }
+            }
         }
     }
+
+    /// Writes any trailing partial line and pending multiline events
+    /// to the sinks
+    fn flush(&mut self) {
+        if let Some(line) = self.lines.flush() {
+            self.write(&line);
+        }
+        self.sinks.iter_mut().for_each(|sink| sink.flush());
+    }
+
+    /// Writes any multiline events which have timed out
+    fn expire(&mut self, now: Instant) {
+        self.sinks.iter_mut().for_each(|sink| sink.expire(now));
+    }
+
+    /// Flushes the connection and closes its sinks, returning their
+    /// writer threads
+    fn close(&mut self) -> Vec<thread::JoinHandle<()>> {
+        self.flush();
+        log_reports(self.history.closed(&self.app, self.pid));
+        self.sinks.drain(..).map(Sink::close).collect()
+    }
+
+    fn write(&mut self, line: &str) {
+        self.history.record(&self.app, self.history_size, line);
+        self.sinks.iter_mut().for_each(|sink| sink.write(line));
+    }
 }
 
+/// How long to wait for an exited process's streams to close
+const CLOSE_TIMEOUT: Duration = Duration::from_millis(500);
+
 /// One `Handler` is used to asynchronously stream many `fd`s to
 /// destinations described by `Stream` structs. It uses a thread with
-/// a `mio:Poll` loop to read. Data is syncronously written to
-/// relevent destinations.
+/// a `mio:Poll` loop to read. Data is passed to a writer thread for
+/// each destination.
+///
+/// Note: Writer threads are per connection so every stream of every
+/// process started costs a thread for each of its destinations.
 ///
-/// Note: Sychronous writes might cause blocking (especially TCP
-/// syslog writes) and may change to async in the future.
+/// Note: Writer threads are fed by bounded queues. When a
+/// destination blocks (especially TCP syslog) and its queue fills up,
+/// lines are dropped or the handler thread waits according to the
+/// destination's `Overflow` policy.
 ///
 /// Note: Write endpoints are constructed and torn down for each write
 /// (ie. TCP connect/send/close and file open/write/close). This is
@@ -95,6 +950,7 @@ impl Connection {
 pub struct Handler {
     channel: cc::Sender<Message>,
     thread: Option<thread::JoinHandle<()>>,
+    history: History,
 }
 
 impl Handler {
@@ -103,25 +959,61 @@ impl Handler {
     /// destintions specified by `Stream`
     pub fn new() -> Handler {
         let (tx, rx) = cc::unbounded();
+        let history = History::default();
+        let thread_history = history.clone();
         Handler {
             channel: tx,
             thread: Some(thread::spawn(move || {
-                handler(&rx);
+                handler(&rx, &thread_history);
             })),
+            history,
         }
     }
 
+    /// Returns the most recent lines captured from application `app`,
+    /// oldest first
+    pub fn recent_output(&self, app: &str) -> Vec<String> {
+        self.history.recent(app)
+    }
+
+    /// Forgets the lines captured from application `app`, eg. when
+    /// it starts again
+    pub fn clear_output(&self, app: &str) {
+        log_reports(self.history.clear(app))
+    }
+
+    /// Logs `message` as a warning along with the lines most recently
+    /// captured from application `app`. If `pid` is given the report
+    /// is put off until everything it wrote has been captured, for up
+    /// to `CLOSE_TIMEOUT` as its streams may be held open by other
+    /// processes.
+    pub fn report(&self, app: &str, pid: Option<u32>, message: String) {
+        log_reports(self.history.report(app, pid, message, CLOSE_TIMEOUT))
+    }
+
     /// Sends a message to background thread to monitor source `fd` and
-    /// write to `Stream`
-    pub fn add_stream(&self, fd: RawFd, stream: Stream) {
+    /// write to each of `destinations`. Lines are captured according
+    /// to `capture`.
+    pub fn add_stream(
+        &self,
+        fd: RawFd,
+        source: Source,
+        destinations: Vec<Destination>,
+        capture: Capture,
+    ) {
         // send message to handler thread
         debug!("Adding stream for fd {}", fd);
-        self.channel.send(Message::Add(fd, stream))
+        // marked open now so a report made before the thread gets the
+        // message still waits for it
+        self.history.opened(&source.app, source.pid);
+        self.channel
+            .send(Message::Add(fd, source, destinations, capture))
     }
 }
 
 /// Before `Handler` is `Drop`ped, send the close message
-/// to the mio thread and wait for it to finish.
+/// to the mio thread and wait for it to finish. It finishes once
+/// everything it has read has been written.
 impl Drop for Handler {
     fn drop(&mut self) {
         self.channel.send(Message::Close);
@@ -132,7 +1024,7 @@ impl Drop for Handler {
 /// Message type for communication between `Handler` instance and
 /// its background logging thread.
 enum Message {
-    Add(RawFd, Stream),
+    Add(RawFd, Source, Vec<Destination>, Capture),
     Close,
 }
 
@@ -142,22 +1034,31 @@ enum Message {
 ///     check for new channels
 ///     poll fds with mio (using a timeout)
 ///     read all readable fds until `WouldBlock`
-///     deregister any fds that have closed
-fn handler(channel: &cc::Receiver<Message>) {
+///     flush partial lines and deregister any fds that have closed
+///     write any multiline events that have timed out
+///
+/// Once closed everything read is written out before returning
+fn handler(channel: &cc::Receiver<Message>, history: &History) {
     let mut connections = Slab::with_capacity(128);
+    // writer threads of closed connections which may still be busy
+    let mut writers = Vec::new();
     let poll = Poll::new().unwrap();
     let mut closed = false;
     while !closed {
         if let Some(message) = channel.try_recv() {
             match message {
-                Message::Add(fd, stream) => {
+                Message::Add(fd, source, destinations, capture) => {
+                    let connection =
+                        Connection::new(fd, &source, destinations, capture, history);
+                    let handle = connections.insert(connection);
                     if let Err(e) = poll.register(
                         &EventedFd(&fd),
-                        Token(connections.insert(Connection::new(fd, stream))),
+                        Token(handle),
                         Ready::readable() | UnixReady::hup(),
                         PollOpt::edge(),
                     ) {
                         error!("Failed to register stream with mio ({})", e);
+                        writers.extend(connections.remove(handle).close());
                     }
                 }
                 Message::Close => closed = true,
@@ -172,67 +1073,92 @@ fn handler(channel: &cc::Receiver<Message>) {
 
         for event in &events {
             let Token(handle) = event.token();
+            let mut eof = false;
             if event.readiness().is_readable() {
-                let mut connection = connections.get_mut(handle).unwrap();
-                loop {
-                    match read_line(&mut connection.source) {
-                        Ok(None) => break,                              // WouldBlock
-                        Ok(Some(ref line)) if line.is_empty() => break, // imminent HUP
-                        Ok(Some(line)) => {
-                            match write_line(&connection.sink, &line[..line.len() - 1]) {
-                                Ok(()) => (),
-                                Err(e) => warn!("Stream redirection failure ({}): {}", e, line),
-                            }
-                        }
-                        Err(e) => {
-                            warn!("Stream error {}", e);
-                            break;
-                        }
-                    }
-                }
+                eof = connections.get_mut(handle).unwrap().read();
             }
-            if UnixReady::from(event.readiness()).is_hup() {
+            if eof || UnixReady::from(event.readiness()).is_hup() {
                 let mut connection = connections.remove(handle);
-                poll.deregister(&EventedFd(&connection.source.into_inner().into_raw_fd()))
+                if !eof {
+                    connection.read();
+                }
+                poll.deregister(&EventedFd(&connection.source.as_raw_fd()))
                     .unwrap();
+                writers.retain(|w: &thread::JoinHandle<()>| !w.is_finished());
+                writers.extend(connection.close());
             }
         }
+
+        let now = Instant::now();
+        connections
+            .iter_mut()
+            .for_each(|(_, connection)| connection.expire(now));
+        log_reports(history.expire(now));
     }
+
+    connections.iter_mut().for_each(|(_, connection)| {
+        connection.read();
+        writers.extend(connection.close());
+    });
+    writers.drain(..).for_each(|writer| {
+        if writer.join().is_err() {
+            error!("Stream writer thread panicked");
+        }
+    });
 }
 
-/// Reads a line from the BufReader.
-///
-/// Note: The underlying fd is already set O_NONBLOCK
-///
-/// Returns `Ok(Some(String))` on success
-///         `Ok(None)` if `BufReader::read_line()` returns `WouldBlock`
-///         `Err(io::Error()` otherwise
-fn read_line(source: &mut BufReader<File>) -> io::Result<Option<String>> {
-    let mut line = String::new();
-    match source.read_line(&mut line) {
-        Ok(_) => Ok(Some(line)),
-        Err(e) => match e.kind() {
-            io::ErrorKind::WouldBlock => Ok(None),
-            _ => Err(e),
-        },
-    }
-}
-
-/// Writes a line to file or to syslog (TCP or UDP)
-fn write_line(sink: &Stream, line: &str) -> io::Result<()> {
+/// Opens a log file for appending, creating it with `mode` and `owner`
+/// if it doesn't exist
+fn open_log(
+    filename: &str,
+    mode: Option<u32>,
+    owner: Option<(libc::uid_t, libc::gid_t)>,
+) -> io::Result<File> {
+    match OpenOptions::new().append(true).open(filename) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        result => return result,
+    }
+    let file = match OpenOptions::new()
+        .append(true)
+        .create_new(true)
+        .mode(mode.unwrap_or(0o666))
+        .open(filename)
+    {
+        // lost a race with whoever else created it
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            return OpenOptions::new().append(true).open(filename)
+        }
+        result => result?,
+    };
+    // the mode given when creating a file is masked by the umask
+    if let Some(mode) = mode {
+        file.set_permissions(Permissions::from_mode(mode))?;
+    }
+    if let Some((uid, gid)) = owner {
+        if unsafe { libc::fchown(file.as_raw_fd(), uid, gid) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(file)
+}
+
+/// Writes a line to file, to syslog (TCP or UDP), to journald or to
+/// the console
+fn write_line(sink: &Stream, source: &Source, line: &str) -> io::Result<()> {
     match sink {
-        Stream::File { filename } => {
-            OpenOptions::new()
-                .create_new(true)
-                .append(true)
-                .open(filename)?
-                .write_all(line.as_ref())?;
-            Ok(())
+        Stream::File {
+            filename,
+            mode,
+            owner,
+        } => {
+            let mut file = open_log(filename, *mode, *owner)?;
+            file.write_all(format!("{}\n", line).as_ref())
         }
         Stream::Syslog {
             address,
             facility,
             severity,
+            rules,
         } => {
             let formatter = Formatter3164 {
                 facility: *facility,
@@ -250,17 +1176,18 @@ fn write_line(sink: &Stream, line: &str) -> io::Result<()> {
                 Address::Tcp(server) => syslog::tcp(formatter, server),
                 Address::Udp { server, local } => syslog::udp(formatter, local, server),
             };
+            let (severity, line) = detect_severity(rules, *severity, line);
             let line = line.to_owned();
 
             match logger {
                 Ok(mut logger) => match severity {
-                    x if *x == LOG_EMERG as u32 => logger.emerg(line),
-                    x if *x == LOG_ALERT as u32 => logger.alert(line),
-                    x if *x == LOG_CRIT as u32 => logger.crit(line),
-                    x if *x == LOG_ERR as u32 => logger.err(line),
-                    x if *x == LOG_WARNING as u32 => logger.warning(line),
-                    x if *x == LOG_NOTICE as u32 => logger.notice(line),
-                    x if *x == LOG_INFO as u32 => logger.info(line),
+                    x if x == LOG_EMERG as u32 => logger.emerg(line),
+                    x if x == LOG_ALERT as u32 => logger.alert(line),
+                    x if x == LOG_CRIT as u32 => logger.crit(line),
+                    x if x == LOG_ERR as u32 => logger.err(line),
+                    x if x == LOG_WARNING as u32 => logger.warning(line),
+                    x if x == LOG_NOTICE as u32 => logger.notice(line),
+                    x if x == LOG_INFO as u32 => logger.info(line),
                     _ => logger.debug(line),
                 }.map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e))),
                 _ => Err(io::Error::new(
@@ -269,15 +1196,67 @@ fn write_line(sink: &Stream, line: &str) -> io::Result<()> {
                 )),
             }
         }
-        #[cfg(test)]
-        Stream::Stdout => Ok(println!("{}", line)),
+        Stream::Journal {
+            socket,
+            identifier,
+            facility,
+            severity,
+            rules,
+        } => {
+            let (severity, line) = detect_severity(rules, *severity, line);
+            let identifier = identifier.as_ref().unwrap_or(&source.app);
+            let fields = [
+                ("MESSAGE", line.to_owned()),
+                ("PRIORITY", severity.to_string()),
+                ("SYSLOG_FACILITY", (*facility as u32 >> 3).to_string()),
+                ("SYSLOG_IDENTIFIER", identifier.to_owned()),
+                ("SYSLOG_PID", source.pid.to_string()),
+                ("RIFFOL_APP", source.app.to_owned()),
+                ("RIFFOL_STREAM", source.name.to_owned()),
+            ];
+            UnixDatagram::unbound()?
+                .send_to(&journal_datagram(&fields), socket)
+                .map(|_| ())
+        }
+        Stream::Console => {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            stdout.write_all(line.as_ref())?;
+            stdout.write_all(b"\n")
+        }
     }
 }
 
+/// Serialises `fields` using the journald native protocol. Values
+/// containing newlines are written as a little-endian 64 bit length
+/// followed by the raw value.
+fn journal_datagram(fields: &[(&str, String)]) -> Vec<u8> {
+    fields.iter().fold(Vec::new(), |mut data, (key, value)| {
+        data.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            data.push(b'\n');
+            let len = value.len() as u64;
+            (0..8).for_each(|i| data.push((len >> (i * 8)) as u8));
+        } else {
+            data.push(b'=');
+        }
+        data.extend_from_slice(value.as_bytes());
+        data.push(b'\n');
+        data
+    })
+}
+
 #[cfg(test)]
 mod test {
-    use super::Stream;
-    use std::os::unix::io::IntoRawFd;
+    use super::{
+        Capture, Continuation, Destination, Encoding, History, Limiter, LineBuffer, Merger,
+        Multiline, Overflow, Overlong, Queue, RateLimit, SeverityRule, Sink, Source, Stream,
+        open_log,
+    };
+    use regex::Regex;
+    use std::time::{Duration, Instant};
+    use std::io::Write;
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
     use std::process::{Command, Stdio};
 
     #[test]
@@ -299,24 +1278,390 @@ mod test {
             .spawn()
             .unwrap();
 
+        let source = |child: &::std::process::Child| Source {
+            app: "test".to_owned(),
+            pid: child.id(),
+            name: "stdout",
+        };
         handler.add_stream(
             child1.stdout.take().unwrap().into_raw_fd(),
-            Stream::Stdout.clone(),
+            source(&child1),
+            vec![Destination::new(Stream::Console)],
+            Capture::default(),
         );
         handler.add_stream(
             child1.stderr.take().unwrap().into_raw_fd(),
-            Stream::Stdout.clone(),
+            source(&child1),
+            vec![Destination::new(Stream::Console)],
+            Capture::default(),
         );
         handler.add_stream(
             child2.stdout.take().unwrap().into_raw_fd(),
-            Stream::Stdout.clone(),
+            source(&child2),
+            vec![Destination::new(Stream::Console)],
+            Capture::default(),
         );
         handler.add_stream(
             child2.stderr.take().unwrap().into_raw_fd(),
-            Stream::Stdout.clone(),
+            source(&child2),
+            vec![Destination::new(Stream::Console)],
+            Capture::default(),
         );
 
         child2.wait().unwrap();
         child1.wait().unwrap();
     }
+
+    #[test]
+    fn test_line_buffer() {
+        let capture = |max_line, overlong, encoding| Capture {
+            max_line,
+            overlong,
+            encoding,
+            history: 0,
+        };
+
+        // partial lines are held until complete or flushed
+        let mut lines = LineBuffer::new(Capture::default(), false);
+        assert_eq!(lines.push(b"one\ntw"), vec!["one"]);
+        assert_eq!(lines.push(b"o\nthree"), vec!["two"]);
+        assert_eq!(lines.flush(), Some("three".to_owned()));
+        assert_eq!(lines.flush(), None);
+
+        // only a pty's carriage returns are dropped
+        let mut lines = LineBuffer::new(Capture::default(), false);
+        assert_eq!(lines.push(b"one\r\n"), vec!["one\r"]);
+        let mut lines = LineBuffer::new(Capture::default(), true);
+        assert_eq!(lines.push(b"one\r\ntwo\r\r\n"), vec!["one", "two\r"]);
+
+        // overlong lines are split or truncated
+        let mut lines = LineBuffer::new(capture(4, Overlong::Split, Encoding::Lossy), false);
+        assert_eq!(lines.push(b"abcdefghij\n"), vec!["abcd", "efgh", "ij"]);
+        let mut lines = LineBuffer::new(capture(4, Overlong::Truncate, Encoding::Lossy), false);
+        assert_eq!(lines.push(b"abcdefghij\nkl\n"), vec!["abcd", "kl"]);
+        assert_eq!(lines.push(b"mnopqr"), vec!["mnop"]);
+        assert_eq!(lines.flush(), None);
+
+        // splits don't break multibyte characters
+        let mut lines = LineBuffer::new(capture(4, Overlong::Split, Encoding::Lossy), false);
+        assert_eq!(lines.push("abc\u{e9}d\n".as_bytes()), vec!["abc", "\u{e9}d"]);
+
+        // invalid UTF-8
+        let mut lines = LineBuffer::new(capture(64, Overlong::Split, Encoding::Lossy), false);
+        assert_eq!(lines.push(b"a\xffb\n"), vec!["a\u{fffd}b"]);
+        let mut lines = LineBuffer::new(capture(64, Overlong::Split, Encoding::Escape), false);
+        assert_eq!(lines.push(b"a\xffb\xc3\n"), vec!["a\\xffb\\xc3"]);
+    }
+
+    #[test]
+    fn test_merger() {
+        let mut merger = Merger::new(Multiline {
+            continuation: Continuation::Indented,
+            timeout: Duration::from_secs(1),
+        });
+        assert_eq!(merger.push("Exception in thread \"main\""), None);
+        assert_eq!(merger.push("\tat Main.main(Main.java:3)"), None);
+        assert_eq!(
+            merger.push("next"),
+            Some("Exception in thread \"main\"\n\tat Main.main(Main.java:3)".to_owned())
+        );
+        assert_eq!(merger.expire(Instant::now()), None);
+        assert_eq!(
+            merger.expire(Instant::now() + Duration::from_secs(2)),
+            Some("next".to_owned())
+        );
+        assert_eq!(merger.flush(), None);
+
+        let mut merger = Merger::new(Multiline {
+            continuation: Continuation::Pattern(Regex::new(r"^(\s|Caused by:)").unwrap()),
+            timeout: Duration::from_secs(1),
+        });
+        assert_eq!(merger.push("Traceback"), None);
+        assert_eq!(merger.push("Caused by: x"), None);
+        assert_eq!(merger.flush(), Some("Traceback\nCaused by: x".to_owned()));
+    }
+
+    #[test]
+    fn test_sink_overflow() {
+        let destination = |overflow| Destination {
+            stream: Stream::File {
+                filename: "/dev/null".to_owned(),
+                mode: None,
+                owner: None,
+            },
+            multiline: None,
+            queue: Queue { size: 2, overflow },
+            rate_limit: None,
+            timestamp: None,
+        };
+        // a sink whose writer can't keep up: fill its queue directly
+        let source = Source {
+            app: "test".to_owned(),
+            pid: 0,
+            name: "stdout",
+        };
+        let mut sink = Sink::new(destination(Overflow::DropNewest), &source);
+        let (tx, rx) = ::crossbeam_channel::bounded(2);
+        sink.writer = tx;
+        sink.queue = rx.clone();
+        (0..4).for_each(|n| sink.write(&n.to_string()));
+        assert_eq!(sink.dropped, 2);
+        assert_eq!(rx.take(2).collect::<Vec<_>>(), vec!["0", "1"]);
+
+        let mut sink = Sink::new(destination(Overflow::DropOldest), &source);
+        let (tx, rx) = ::crossbeam_channel::bounded(2);
+        sink.writer = tx;
+        sink.queue = rx.clone();
+        (0..4).for_each(|n| sink.write(&n.to_string()));
+        assert_eq!(sink.dropped, 2);
+        assert_eq!(rx.take(2).collect::<Vec<_>>(), vec!["2", "3"]);
+    }
+
+    #[test]
+    fn test_detect_severity() {
+        use super::detect_severity;
+
+        let rules = vec![
+            SeverityRule::Prefix,
+            SeverityRule::Key("level".to_owned()),
+            SeverityRule::Pattern(Regex::new("WARN").unwrap(), 4),
+        ];
+        assert_eq!(detect_severity(&rules, 3, "<6>banner"), (6, "banner"));
+        assert_eq!(detect_severity(&rules, 3, "<9>banner"), (3, "<9>banner"));
+        assert_eq!(
+            detect_severity(&rules, 3, "ts=1 level=\"INFO\" msg=x"),
+            (6, "ts=1 level=\"INFO\" msg=x")
+        );
+        assert_eq!(detect_severity(&rules, 3, "level=odd WARN"), (4, "level=odd WARN"));
+        assert_eq!(detect_severity(&rules, 3, "plain"), (3, "plain"));
+        assert_eq!(detect_severity(&[], 7, "<1>x"), (7, "<1>x"));
+    }
+
+    #[test]
+    fn test_journal() {
+        use super::write_line;
+        use std::os::unix::net::UnixDatagram;
+        use syslog::Facility;
+
+        let socket =
+            ::std::env::temp_dir().join(format!("riffol-journal-{}", ::std::process::id()));
+        let _ = ::std::fs::remove_file(&socket);
+        let journal = UnixDatagram::bind(&socket).unwrap();
+        let stream = Stream::Journal {
+            socket: socket.to_string_lossy().into_owned(),
+            identifier: None,
+            facility: Facility::LOG_DAEMON,
+            severity: 3,
+            rules: vec![SeverityRule::Prefix],
+        };
+        let source = Source {
+            app: "www".to_owned(),
+            pid: 42,
+            name: "stderr",
+        };
+        let mut data = [0u8; 1024];
+
+        write_line(&stream, &source, "<6>started").unwrap();
+        let n = journal.recv(&mut data).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&data[..n]),
+            "MESSAGE=started\nPRIORITY=6\nSYSLOG_FACILITY=3\nSYSLOG_IDENTIFIER=www\n\
+             SYSLOG_PID=42\nRIFFOL_APP=www\nRIFFOL_STREAM=stderr\n"
+        );
+
+        write_line(&stream, &source, "two\nlines").unwrap();
+        journal.recv(&mut data).unwrap();
+        let expected = b"MESSAGE\n\x09\0\0\0\0\0\0\0two\nlines\nPRIORITY=3\n";
+        assert_eq!(&data[..expected.len()], &expected[..]);
+
+        ::std::fs::remove_file(&socket).unwrap();
+    }
+
+    #[test]
+    fn test_history() {
+        let history = History::default();
+        (0..5).for_each(|n| history.record("app", 3, &n.to_string()));
+        history.record("other", 0, "ignored");
+        assert_eq!(history.recent("app"), vec!["2", "3", "4"]);
+        assert!(history.recent("other").is_empty());
+        history.clear("app");
+        assert!(history.recent("app").is_empty());
+
+        // reports wait for a process's streams to close
+        let report = |pid, message: &str| {
+            history.report("app", pid, message.to_owned(), Duration::from_secs(60))
+        };
+        history.record("app", 3, "last");
+        assert_eq!(report(None, "now"), vec!["now. Last output:\nlast"]);
+        history.opened("app", 1);
+        history.opened("app", 1);
+        history.opened("app", 2);
+        assert!(report(Some(1), "one").is_empty());
+        assert!(history.closed("app", 1).is_empty());
+        history.record("app", 3, "later");
+        assert_eq!(history.closed("app", 1), vec!["one. Last output:\nlast\nlater"]);
+        assert!(report(Some(2), "two").is_empty());
+        assert!(history.expire(Instant::now()).is_empty());
+        assert_eq!(history.expire(Instant::now() + Duration::from_secs(60)).len(), 1);
+        // or are made before the lines are cleared
+        assert!(report(Some(2), "three").is_empty());
+        assert_eq!(history.clear("app"), vec!["three. Last output:\nlast\nlater"]);
+        assert_eq!(report(Some(3), "four"), vec!["four"]);
+    }
+
+    #[test]
+    fn test_limiter() {
+        let start = Instant::now();
+        let mut limiter = Limiter::new(RateLimit {
+            lines: Some(2),
+            bytes: Some(10),
+            interval: Duration::from_secs(1),
+            burst: Some(3),
+        });
+        // burst of 3 lines, then suppressed
+        assert!((0..5).filter(|_| limiter.allow(1, start)).count() == 3);
+        assert_eq!(limiter.summary(start, false), None);
+        let later = start + Duration::from_secs(1);
+        assert_eq!(
+            limiter.summary(later, false),
+            Some("riffol: 2 messages suppressed".to_owned())
+        );
+        // 2 lines refilled but only 10 bytes
+        assert!(limiter.allow(8, later));
+        assert!(!limiter.allow(3, later));
+        assert_eq!(
+            limiter.summary(later, true),
+            Some("riffol: 1 messages suppressed".to_owned())
+        );
+        // a line longer than the byte limit takes the whole bucket
+        let later = later + Duration::from_secs(1);
+        assert!(limiter.allow(100, later));
+        assert!(!limiter.allow(1, later));
+    }
+
+    #[test]
+    fn test_open_log() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let filename = ::std::env::temp_dir().join(format!("riffol-log-{}", ::std::process::id()));
+        let filename = filename.to_str().unwrap();
+        let mode = || fs::metadata(filename).unwrap().permissions().mode() & 0o7777;
+        open_log(filename, Some(0o640), None).unwrap();
+        assert_eq!(mode(), 0o640);
+        // only files being created are given the mode
+        open_log(filename, Some(0o600), None).unwrap();
+        assert_eq!(mode(), 0o640);
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_close() {
+        use std::fs;
+
+        let filename =
+            ::std::env::temp_dir().join(format!("riffol-close-{}", ::std::process::id()));
+        let filename = filename.to_str().unwrap();
+        let destination = Destination {
+            stream: Stream::File {
+                filename: filename.to_owned(),
+                mode: None,
+                owner: None,
+            },
+            multiline: Some(Multiline {
+                continuation: Continuation::Indented,
+                timeout: Duration::from_secs(60),
+            }),
+            queue: Queue::default(),
+            rate_limit: Some(RateLimit {
+                lines: Some(1),
+                bytes: None,
+                interval: Duration::from_secs(60),
+                burst: None,
+            }),
+            timestamp: None,
+        };
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { ::libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut input = unsafe { ::std::fs::File::from_raw_fd(fds[1]) };
+        input.write_all(b"first\n  more\nsecond\nthird").unwrap();
+
+        // the source is still open when the handler closes, yet the
+        // pending event, partial line and summary are all written
+        let handler = super::Handler::new();
+        handler.add_stream(
+            fds[0],
+            Source {
+                app: "test".to_owned(),
+                pid: 0,
+                name: "stdout",
+            },
+            vec![destination],
+            Capture::default(),
+        );
+        drop(handler);
+        assert_eq!(
+            fs::read_to_string(filename).unwrap(),
+            "first\n  more\nriffol: 2 messages suppressed\n"
+        );
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_fan_out() {
+        use std::fs;
+
+        let filename = |n| {
+            let path = ::std::env::temp_dir()
+                .join(format!("riffol-fan-out-{}-{}", ::std::process::id(), n));
+            path.to_str().unwrap().to_owned()
+        };
+        let destination = |filename: &str, rate_limit| Destination {
+            rate_limit,
+            ..Destination::new(Stream::File {
+                filename: filename.to_owned(),
+                mode: None,
+                owner: None,
+            })
+        };
+        let (first, second) = (filename(1), filename(2));
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { ::libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut input = unsafe { ::std::fs::File::from_raw_fd(fds[1]) };
+        input.write_all(b"one\ntwo\n").unwrap();
+        drop(input);
+
+        // every line reaches each destination, which is limited
+        // independently
+        let handler = super::Handler::new();
+        handler.add_stream(
+            fds[0],
+            Source {
+                app: "test".to_owned(),
+                pid: 0,
+                name: "stdout",
+            },
+            vec![
+                destination(&first, None),
+                destination(
+                    &second,
+                    Some(RateLimit {
+                        lines: Some(1),
+                        bytes: None,
+                        interval: Duration::from_secs(60),
+                        burst: None,
+                    }),
+                ),
+            ],
+            Capture::default(),
+        );
+        drop(handler);
+        assert_eq!(fs::read_to_string(&first).unwrap(), "one\ntwo\n");
+        assert_eq!(
+            fs::read_to_string(&second).unwrap(),
+            "one\nriffol: 1 messages suppressed\n"
+        );
+        fs::remove_file(&first).unwrap();
+        fs::remove_file(&second).unwrap();
+    }
 }
@@ -0,0 +1,231 @@
+// Copyright (c) 2018, [Ribose Inc](https://www.ribose.com).
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions and the following disclaimer.
+// 2. Redistributions in binary form must reproduce the above copyright
+//    notice, this list of conditions and the following disclaimer in the
+//    documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS
+// ``AS IS'' AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT
+// LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR
+// A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT
+// OWNER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+// SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT
+// LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE,
+// DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY
+// THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+// (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use libc;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Resources used by one or more processes
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Usage {
+    pub user: Duration,
+    pub system: Duration,
+    /// Peak resident set size in KiB
+    pub max_rss: u64,
+}
+
+impl Usage {
+    pub fn from_rusage(rusage: &libc::rusage) -> Self {
+        let duration = |t: &libc::timeval| {
+            Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+        };
+        Usage {
+            user: duration(&rusage.ru_utime),
+            system: duration(&rusage.ru_stime),
+            max_rss: rusage.ru_maxrss as u64,
+        }
+    }
+
+    pub fn cpu_time(&self) -> Duration {
+        self.user + self.system
+    }
+
+    /// Combines the usage of two sets of processes. CPU times are
+    /// summed and the higher of the peak RSSs is taken.
+    fn add(&self, other: &Usage) -> Usage {
+        Usage {
+            user: self.user + other.user,
+            system: self.system + other.system,
+            max_rss: self.max_rss.max(other.max_rss),
+        }
+    }
+}
+
+/// A duration in seconds for display
+pub fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_millis()) / 1000.0
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "CPU time {:.3}s (user {:.3}s, system {:.3}s), peak RSS {} KiB",
+            secs(self.cpu_time()),
+            secs(self.user),
+            secs(self.system),
+            self.max_rss
+        )
+    }
+}
+
+/// Samples the resource use of a running process from
+/// `/proc/<pid>/stat` and `/proc/<pid>/status`
+pub fn sample(pid: u32) -> io::Result<Usage> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Bad /proc/{} data", pid));
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    // the command name may contain spaces so fields are counted
+    // from after it, starting with the third field (state)
+    let fields = stat[stat.rfind(')').ok_or_else(invalid)? + 1..]
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    let ticks = |i: usize| {
+        fields
+            .get(i)
+            .and_then(|t| t.parse::<u64>().ok())
+            .ok_or_else(invalid)
+    };
+    let (utime, stime) = (ticks(11)?, ticks(12)?);
+    let hz = match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        hz if hz > 0 => hz as u64,
+        _ => 100,
+    };
+    let duration = |t: u64| Duration::from_secs(t / hz) + Duration::from_millis(t % hz * 1000 / hz);
+    let status = fs::read_to_string(format!("/proc/{}/status", pid))?;
+    let max_rss = status
+        .lines()
+        .find(|l| l.starts_with("VmHWM:"))
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse().ok())
+        .unwrap_or(0);
+    Ok(Usage {
+        user: duration(utime),
+        system: duration(stime),
+        max_rss,
+    })
+}
+
+/// An application run's resource use so far
+#[derive(Debug)]
+struct Run {
+    start: Instant,
+    /// The use of reaped processes
+    reaped: Usage,
+    /// The latest sample of the running main process
+    sampled: Usage,
+}
+
+/// Resources used by an application across all its runs
+#[derive(Debug, Default)]
+pub struct Accounting {
+    /// The number of completed runs
+    pub runs: u64,
+    pub usage: Usage,
+    pub run_time: Duration,
+    run: Option<Run>,
+}
+
+impl Accounting {
+    pub fn start(&mut self) {
+        self.run = Some(Run {
+            start: Instant::now(),
+            reaped: Usage::default(),
+            sampled: Usage::default(),
+        });
+    }
+
+    /// Adds the use of a reaped process. `main` is true if it was
+    /// the process being sampled.
+    pub fn add(&mut self, usage: &Usage, main: bool) {
+        match self.run.as_mut() {
+            Some(run) => {
+                run.reaped = run.reaped.add(usage);
+                if main {
+                    run.sampled = Usage::default();
+                }
+            }
+            None => self.usage = self.usage.add(usage),
+        }
+    }
+
+    pub fn sample(&mut self, usage: &Usage) {
+        if let Some(run) = self.run.as_mut() {
+            run.sampled = *usage;
+        }
+    }
+
+    /// Ends the current run returning its usage and duration
+    pub fn finish(&mut self) -> Option<(Usage, Duration)> {
+        self.run.take().map(|run| {
+            let usage = run.reaped.add(&run.sampled);
+            let duration = run.start.elapsed();
+            self.runs += 1;
+            self.usage = self.usage.add(&usage);
+            self.run_time += duration;
+            (usage, duration)
+        })
+    }
+
+    /// The usage of all runs, including the current one so far
+    pub fn total(&self) -> (Usage, Duration) {
+        match self.run.as_ref() {
+            Some(run) => (
+                self.usage.add(&run.reaped).add(&run.sampled),
+                self.run_time + run.start.elapsed(),
+            ),
+            None => (self.usage, self.run_time),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sample, Accounting, Usage};
+    use std::process;
+    use std::time::Duration;
+
+    #[test]
+    fn test_sample() {
+        let usage = sample(process::id()).unwrap();
+        assert!(usage.max_rss > 0);
+        assert!(sample(0).is_err());
+    }
+
+    #[test]
+    fn test_accounting() {
+        let usage = |secs, max_rss| Usage {
+            user: Duration::from_secs(secs),
+            system: Duration::from_secs(1),
+            max_rss,
+        };
+        let mut accounting = Accounting::default();
+        accounting.start();
+        accounting.sample(&usage(5, 100));
+        assert_eq!(accounting.total().0, usage(5, 100));
+        // the main process's final usage replaces its samples
+        accounting.add(&usage(6, 90), true);
+        accounting.add(&usage(1, 200), false);
+        let (run, _) = accounting.finish().unwrap();
+        assert_eq!(run.user, Duration::from_secs(7));
+        assert_eq!(run.system, Duration::from_secs(2));
+        assert_eq!(run.max_rss, 200);
+        assert!(accounting.finish().is_none());
+        assert_eq!(accounting.runs, 1);
+        assert_eq!(
+            format!("{}", usage(1, 10)),
+            "CPU time 2.000s (user 1.000s, system 1.000s), peak RSS 10 KiB"
+        );
+    }
+}